@@ -0,0 +1,282 @@
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error::Error;
+use thiserror::Error;
+use tokio::{fs::File, io::AsyncReadExt};
+
+type HmacSha256 = Hmac<Sha256>;
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// Size of a regular amiibo dump (NTAG215 without the manufacturer signature)
+pub const AMIIBO_SIZE: usize = 540;
+const MASTER_KEY_SIZE: usize = 80;
+const KEY_FILE_SIZE: usize = MASTER_KEY_SIZE * 2;
+
+const HMAC_POS_DATA: usize = 0x008;
+const HMAC_POS_TAG: usize = 0x1B4;
+
+/// One half of the key_retail.bin file.
+/// Layout: hmac key (16), type string (14), reserved (1), magic bytes size (1), magic bytes (16),
+/// xor pad (32)
+#[derive(Debug, Clone)]
+struct MasterKey {
+    hmac_key: [u8; 16],
+    type_string: [u8; 14],
+    magic_bytes_size: usize,
+    magic_bytes: [u8; 16],
+    xor_pad: [u8; 32],
+}
+
+struct DerivedKeys {
+    aes_key: [u8; 16],
+    aes_iv: [u8; 16],
+    hmac_key: [u8; 16],
+}
+
+impl MasterKey {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, AmiiboCryptoError> {
+        let magic_bytes_size = bytes[31] as usize;
+        if magic_bytes_size > 16 {
+            return Err(AmiiboCryptoError::InvalidMagicBytesSize(magic_bytes_size));
+        }
+        Ok(Self {
+            hmac_key: bytes[0..16].try_into().unwrap(),
+            type_string: bytes[16..30].try_into().unwrap(),
+            magic_bytes_size,
+            magic_bytes: bytes[32..48].try_into().unwrap(),
+            xor_pad: bytes[48..80].try_into().unwrap(),
+        })
+    }
+
+    /// Derives the per-tag keys from the master key and the tag contents (in internal layout)
+    fn derive_keys(&self, internal: &[u8]) -> DerivedKeys {
+        let mut base_seed = [0u8; 64];
+        base_seed[0x00..0x02].copy_from_slice(&internal[0x029..0x02B]);
+        base_seed[0x10..0x18].copy_from_slice(&internal[0x1D4..0x1DC]);
+        base_seed[0x18..0x20].copy_from_slice(&internal[0x1D4..0x1DC]);
+        base_seed[0x20..0x40].copy_from_slice(&internal[0x1E8..0x208]);
+
+        // Type string is copied together with its null terminator
+        let type_string_len = self
+            .type_string
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(self.type_string.len(), |pos| pos + 1);
+
+        let mut seed = Vec::with_capacity(type_string_len + 64);
+        seed.extend_from_slice(&self.type_string[..type_string_len]);
+        seed.extend_from_slice(&base_seed[..(16 - self.magic_bytes_size)]);
+        seed.extend_from_slice(&self.magic_bytes[..self.magic_bytes_size]);
+        seed.extend_from_slice(&base_seed[0x10..0x20]);
+        seed.extend(
+            base_seed[0x20..0x40]
+                .iter()
+                .zip(self.xor_pad)
+                .map(|(byte, pad)| byte ^ pad),
+        );
+
+        // HMAC-SHA256 DRBG, each iteration is prefixed with a big-endian counter
+        let mut output = Vec::with_capacity(64);
+        for iteration in 0u16..2 {
+            let mut mac = HmacSha256::new_from_slice(&self.hmac_key).unwrap();
+            mac.update(&iteration.to_be_bytes());
+            mac.update(&seed);
+            output.extend_from_slice(&mac.finalize().into_bytes());
+        }
+
+        DerivedKeys {
+            aes_key: output[0..16].try_into().unwrap(),
+            aes_iv: output[16..32].try_into().unwrap(),
+            hmac_key: output[32..48].try_into().unwrap(),
+        }
+    }
+}
+
+impl DerivedKeys {
+    fn cipher(&self, data: &mut [u8]) {
+        let mut cipher = Aes128Ctr::new(&self.aes_key.into(), &self.aes_iv.into());
+        cipher.apply_keystream(&mut data[0x02C..0x1B4]);
+    }
+
+    fn hmac(&self, data: &[u8]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(&self.hmac_key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// Amiibo master keys, loaded from a key_retail.bin file. Used to decrypt and re-sign amiibo
+/// dumps.
+#[derive(Debug, Clone)]
+pub struct AmiiboKeys {
+    data: MasterKey,
+    tag: MasterKey,
+}
+
+impl AmiiboKeys {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AmiiboCryptoError> {
+        if bytes.len() != KEY_FILE_SIZE {
+            return Err(AmiiboCryptoError::InvalidKeyFileSize(bytes.len()));
+        }
+        Ok(Self {
+            data: MasterKey::from_bytes(&bytes[..MASTER_KEY_SIZE])?,
+            tag: MasterKey::from_bytes(&bytes[MASTER_KEY_SIZE..])?,
+        })
+    }
+
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut reader = File::open(source).await?;
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).await?;
+        Ok(Self::from_bytes(&buf)?)
+    }
+
+    /// Decrypts an amiibo dump and verifies its signatures. The result keeps the tag layout.
+    pub fn decrypt(&self, tag: &[u8]) -> Result<Vec<u8>, AmiiboCryptoError> {
+        if tag.len() < AMIIBO_SIZE {
+            return Err(AmiiboCryptoError::InvalidTagSize(tag.len()));
+        }
+        let internal = tag_to_internal(tag);
+        let data_keys = self.data.derive_keys(&internal);
+        let tag_keys = self.tag.derive_keys(&internal);
+
+        let mut plain = internal.clone();
+        data_keys.cipher(&mut plain);
+        let tag_hmac = tag_keys.hmac(&plain[0x1D4..0x208]);
+        plain[HMAC_POS_TAG..(HMAC_POS_TAG + 32)].copy_from_slice(&tag_hmac);
+        let data_hmac = data_keys.hmac(&plain[0x029..0x208]);
+        plain[HMAC_POS_DATA..(HMAC_POS_DATA + 32)].copy_from_slice(&data_hmac);
+
+        if plain[HMAC_POS_DATA..(HMAC_POS_DATA + 32)]
+            != internal[HMAC_POS_DATA..(HMAC_POS_DATA + 32)]
+            || plain[HMAC_POS_TAG..(HMAC_POS_TAG + 32)]
+                != internal[HMAC_POS_TAG..(HMAC_POS_TAG + 32)]
+        {
+            return Err(AmiiboCryptoError::SignatureMismatch);
+        }
+
+        let mut result = tag.to_vec();
+        result[..AMIIBO_SIZE].copy_from_slice(&internal_to_tag(&plain));
+        Ok(result)
+    }
+
+    /// Signs and encrypts a decrypted amiibo dump in tag layout, inverse of `decrypt`
+    pub fn encrypt(&self, plain_tag: &[u8]) -> Result<Vec<u8>, AmiiboCryptoError> {
+        if plain_tag.len() < AMIIBO_SIZE {
+            return Err(AmiiboCryptoError::InvalidTagSize(plain_tag.len()));
+        }
+        let mut plain = tag_to_internal(plain_tag);
+        let tag_keys = self.tag.derive_keys(&plain);
+        let data_keys = self.data.derive_keys(&plain);
+
+        let tag_hmac = tag_keys.hmac(&plain[0x1D4..0x208]);
+        plain[HMAC_POS_TAG..(HMAC_POS_TAG + 32)].copy_from_slice(&tag_hmac);
+        let data_hmac = data_keys.hmac(&plain[0x029..0x208]);
+        plain[HMAC_POS_DATA..(HMAC_POS_DATA + 32)].copy_from_slice(&data_hmac);
+        data_keys.cipher(&mut plain);
+
+        let mut result = plain_tag.to_vec();
+        result[..AMIIBO_SIZE].copy_from_slice(&internal_to_tag(&plain));
+        Ok(result)
+    }
+}
+
+/// (internal offset, tag offset, length) of the blocks that get shuffled between the on-tag layout
+/// and the layout the keys and signatures are computed over
+const INTERNAL_LAYOUT: [(usize, usize, usize); 7] = [
+    (0x000, 0x008, 0x008),
+    (0x008, 0x080, 0x020),
+    (0x028, 0x010, 0x024),
+    (0x04C, 0x0A0, 0x168),
+    (0x1B4, 0x034, 0x020),
+    (0x1D4, 0x000, 0x008),
+    (0x1DC, 0x054, 0x02C),
+];
+
+fn tag_to_internal(tag: &[u8]) -> Vec<u8> {
+    let mut internal = vec![0; AMIIBO_SIZE];
+    internal[0x208..].copy_from_slice(&tag[0x208..AMIIBO_SIZE]);
+    for (intl, tag_pos, len) in INTERNAL_LAYOUT {
+        internal[intl..(intl + len)].copy_from_slice(&tag[tag_pos..(tag_pos + len)]);
+    }
+    internal
+}
+
+fn internal_to_tag(internal: &[u8]) -> Vec<u8> {
+    let mut tag = vec![0; AMIIBO_SIZE];
+    tag[0x208..].copy_from_slice(&internal[0x208..AMIIBO_SIZE]);
+    for (intl, tag_pos, len) in INTERNAL_LAYOUT {
+        tag[tag_pos..(tag_pos + len)].copy_from_slice(&internal[intl..(intl + len)]);
+    }
+    tag
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum AmiiboCryptoError {
+    #[error("Key file must be 160 bytes long, got {0}")]
+    InvalidKeyFileSize(usize),
+    #[error("Invalid magic bytes size {0} in key file")]
+    InvalidMagicBytesSize(usize),
+    #[error("Amiibo dump must be at least 540 bytes long, got {0}")]
+    InvalidTagSize(usize),
+    #[error("Amiibo signature mismatch, wrong keys or corrupted dump")]
+    SignatureMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Made up keys, the real ones aren't distributable
+    fn keys() -> AmiiboKeys {
+        let mut bytes = (0..KEY_FILE_SIZE)
+            .map(|i| (i * 37 + 11) as u8)
+            .collect::<Vec<_>>();
+        bytes[16..30].copy_from_slice(b"unfixed infos\0");
+        bytes[31] = 14;
+        bytes[MASTER_KEY_SIZE + 16..MASTER_KEY_SIZE + 30].copy_from_slice(b"locked secret\0");
+        bytes[MASTER_KEY_SIZE + 31] = 16;
+        AmiiboKeys::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn decrypting_and_encrypting_round_trips() {
+        let keys = keys();
+        // Includes the manufacturer signature, which is kept as it is
+        let plain = (0..(AMIIBO_SIZE + 32))
+            .map(|i| (i * 13 + 5) as u8)
+            .collect::<Vec<_>>();
+        let encrypted = keys.encrypt(&plain).unwrap();
+        assert_ne!(encrypted[0xA0..0x100], plain[0xA0..0x100]);
+        assert_eq!(encrypted[AMIIBO_SIZE..], plain[AMIIBO_SIZE..]);
+
+        let decrypted = keys.decrypt(&encrypted).unwrap();
+        assert_eq!(keys.encrypt(&decrypted).unwrap(), encrypted);
+        // Only the signatures (data at 0x80, tag at 0x34) were computed by encrypt
+        let mut signed = plain.clone();
+        signed[0x80..0xA0].copy_from_slice(&decrypted[0x80..0xA0]);
+        signed[0x34..0x54].copy_from_slice(&decrypted[0x34..0x54]);
+        assert_eq!(decrypted, signed);
+    }
+
+    #[test]
+    fn tampered_dumps_are_rejected() {
+        let keys = keys();
+        let mut encrypted = keys.encrypt(&[0x42; AMIIBO_SIZE]).unwrap();
+        encrypted[0x100] ^= 1;
+        assert!(matches!(
+            keys.decrypt(&encrypted),
+            Err(AmiiboCryptoError::SignatureMismatch)
+        ));
+        assert!(matches!(
+            keys.decrypt(&encrypted[..100]),
+            Err(AmiiboCryptoError::InvalidTagSize(100))
+        ));
+        assert!(matches!(
+            AmiiboKeys::from_bytes(&[0; 80]),
+            Err(AmiiboCryptoError::InvalidKeyFileSize(80))
+        ));
+    }
+}
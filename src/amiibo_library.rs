@@ -0,0 +1,184 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use thiserror::Error;
+use tokio::fs;
+
+use crate::{
+    amiibo::{AmiiboKeys, AMIIBO_SIZE},
+    nfc_tag::NFCTag,
+};
+
+/// An amiibo dump found in the library directory
+#[derive(Debug, Clone)]
+pub struct AmiiboEntry {
+    /// File name without the extension
+    pub name: String,
+    pub path: PathBuf,
+    pub uid: [u8; 7],
+    /// Game and character ID from the model info. The model info isn't encrypted, so this is
+    /// always available.
+    pub character_id: u16,
+    /// Nickname set by the owner, only available if the library was indexed with keys
+    pub nickname: Option<String>,
+}
+
+impl AmiiboEntry {
+    fn from_bytes(path: PathBuf, data: &[u8], keys: Option<&AmiiboKeys>) -> Self {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let uid = [&data[0..3], &data[4..8]].concat().try_into().unwrap();
+        let character_id = u16::from_be_bytes([data[0x54], data[0x55]]);
+        let nickname = keys.and_then(|keys| match keys.decrypt(data) {
+            // Nickname is stored as 10 UTF-16 BE characters, padded with zeroes
+            Ok(plain) => Some(
+                char::decode_utf16(
+                    plain[0x20..0x34]
+                        .chunks_exact(2)
+                        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                        .take_while(|&c| c != 0),
+                )
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+            ),
+            Err(why) => {
+                warn!("Couldn't decrypt {}: {}", path.display(), why);
+                None
+            }
+        });
+        Self {
+            name,
+            path,
+            uid,
+            character_id,
+            nickname,
+        }
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.name.to_lowercase() == query
+            || hex::encode(self.uid) == query
+            || format!("{:04x}", self.character_id) == query
+            || self
+                .nickname
+                .as_ref()
+                .is_some_and(|nickname| nickname.to_lowercase() == query)
+    }
+}
+
+impl Display for AmiiboEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} uid:{} character:{:04x}",
+            self.name,
+            hex::encode(self.uid),
+            self.character_id
+        )?;
+        if let Some(nickname) = &self.nickname {
+            write!(f, " nickname:{}", nickname)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the amiibo dumps (.bin files) in a directory
+#[derive(Debug, Clone)]
+pub struct AmiiboLibrary {
+    directory: PathBuf,
+    entries: Vec<AmiiboEntry>,
+    current: Option<usize>,
+}
+
+impl AmiiboLibrary {
+    /// Indexes all .bin files in `directory`. If keys are given, the dumps are decrypted to read
+    /// their nicknames. Entries are read from the amiibo model info, so files smaller than an
+    /// amiibo dump (`AMIIBO_SIZE`), like NTAG213 or MIFARE Ultralight dumps, are skipped.
+    pub async fn index(
+        directory: &Path,
+        keys: Option<&AmiiboKeys>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut entries = vec![];
+        let mut dir = fs::read_dir(directory).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if !path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("bin"))
+            {
+                continue;
+            }
+            let data = fs::read(&path).await?;
+            if data.len() < AMIIBO_SIZE {
+                warn!("Skipping {}, too small for an amiibo", path.display());
+                continue;
+            }
+            entries.push(AmiiboEntry::from_bytes(path, &data, keys));
+        }
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self {
+            directory: directory.into(),
+            entries,
+            current: None,
+        })
+    }
+
+    #[inline]
+    pub fn get_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &[AmiiboEntry] {
+        &self.entries
+    }
+
+    /// Entry that was loaded last
+    #[inline]
+    pub fn current(&self) -> Option<&AmiiboEntry> {
+        self.entries.get(self.current?)
+    }
+
+    /// Looks an entry up by file name, UID or character ID (both in hex) or nickname
+    pub fn find(&self, query: &str) -> Option<&AmiiboEntry> {
+        self.entries.iter().find(|entry| entry.matches(query))
+    }
+
+    /// Loads the tag of the entry matching `query`, see `find`
+    pub async fn load(&mut self, query: &str) -> Result<NFCTag, Box<dyn Error + Send + Sync>> {
+        let idx = self
+            .entries
+            .iter()
+            .position(|entry| entry.matches(query))
+            .ok_or_else(|| AmiiboNotFound(query.into()))?;
+        self.load_idx(idx).await
+    }
+
+    /// Loads the entry after the current one, wrapping around at the end of the library
+    pub async fn cycle(&mut self) -> Result<NFCTag, Box<dyn Error + Send + Sync>> {
+        if self.entries.is_empty() {
+            return Err(AmiiboNotFound("any".into()).into());
+        }
+        let idx = self
+            .current
+            .map_or(0, |current| (current + 1) % self.entries.len());
+        self.load_idx(idx).await
+    }
+
+    async fn load_idx(&mut self, idx: usize) -> Result<NFCTag, Box<dyn Error + Send + Sync>> {
+        let tag = NFCTag::load(&self.entries[idx].path.to_string_lossy()).await?;
+        self.current = Some(idx);
+        Ok(tag)
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error("No amiibo matching {0} in the library")]
+pub struct AmiiboNotFound(String);
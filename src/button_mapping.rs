@@ -0,0 +1,138 @@
+use std::error::Error;
+
+use hashbrown::HashMap;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::button_state::Button;
+
+/// Translates the buttons the user asks for into the buttons the controller reports. Example
+/// config:
+/// ```yaml
+/// xbox:
+///   swap_ab: true
+///   swap_xy: true
+///   aliases:
+///     jump: a
+/// sideways:
+///   sideways: true
+///   remap:
+///     minus: plus
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ButtonProfile {
+    /// Extra (case insensitive) names for buttons, resolved before remapping
+    pub aliases: HashMap<String, Button>,
+    /// Takes precedence over the other remapping options
+    pub remap: HashMap<Button, Button>,
+    pub swap_ab: bool,
+    pub swap_xy: bool,
+    /// L/ZL and R/ZR become SL and SR, for holding a single Joy-Con sideways
+    pub sideways: bool,
+}
+
+impl ButtonProfile {
+    /// Button named `name`, which is either an alias or a button name
+    pub fn resolve(&self, name: &str) -> Option<Button> {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|(_, button)| *button)
+            .or_else(|| name.parse().ok())
+    }
+
+    /// Button actually reported for `button`
+    pub fn map(&self, button: Button) -> Button {
+        if let Some(mapped) = self.remap.get(&button) {
+            return *mapped;
+        }
+        match button {
+            Button::A if self.swap_ab => Button::B,
+            Button::B if self.swap_ab => Button::A,
+            Button::X if self.swap_xy => Button::Y,
+            Button::Y if self.swap_xy => Button::X,
+            Button::L | Button::Zl if self.sideways => Button::Sl,
+            Button::R | Button::Zr if self.sideways => Button::Sr,
+            _ => button,
+        }
+    }
+}
+
+/// Named profiles, usually loaded from a YAML file mapping names to `ButtonProfile`s
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct ButtonProfiles {
+    profiles: HashMap<String, ButtonProfile>,
+}
+
+impl ButtonProfiles {
+    pub fn from_yaml(config: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(config)
+    }
+
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let config = tokio::fs::read_to_string(source).await?;
+        Ok(Self::from_yaml(&config)?)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ButtonProfile, ProfileNotFound> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| ProfileNotFound(name.into()))
+    }
+
+    /// Profile names in alphabetical order
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.profiles.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error("No button profile named {0}")]
+pub struct ProfileNotFound(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = "xbox:\n\
+        \x20 swap_ab: true\n\
+        \x20 swap_xy: true\n\
+        \x20 aliases:\n\
+        \x20   jump: a\n\
+        sideways:\n\
+        \x20 sideways: true\n\
+        \x20 remap:\n\
+        \x20   minus: plus\n\
+        \x20   zl: zr\n";
+
+    #[test]
+    fn aliases_resolve_before_button_names() {
+        let profiles = ButtonProfiles::from_yaml(PROFILES).unwrap();
+        let xbox = profiles.get("xbox").unwrap();
+        assert_eq!(xbox.resolve("JUMP"), Some(Button::A));
+        assert_eq!(xbox.resolve("zl"), Some(Button::Zl));
+        assert_eq!(xbox.resolve("jump2"), None);
+        assert_eq!(profiles.names(), ["sideways", "xbox"]);
+        assert!(profiles.get("ps").is_err());
+    }
+
+    #[test]
+    fn swaps_and_sideways_map_the_buttons() {
+        use Button::*;
+
+        let profiles = ButtonProfiles::from_yaml(PROFILES).unwrap();
+        let xbox = profiles.get("xbox").unwrap();
+        for (button, reported) in [(A, B), (B, A), (X, Y), (Y, X), (L, L)] {
+            assert_eq!(xbox.map(button), reported, "{}", button);
+        }
+        // Remapping takes precedence over sideways
+        let sideways = profiles.get("sideways").unwrap();
+        for (button, reported) in [(L, Sl), (Zl, Zr), (R, Sr), (Zr, Sr), (Minus, Plus), (A, A)] {
+            assert_eq!(sideways.map(button), reported, "{}", button);
+        }
+    }
+}
@@ -1,201 +1,424 @@
-use std::{iter::repeat, time::Duration};
+use std::{str::FromStr, time::Duration};
 
-use hashbrown::HashMap;
+use bitflags::bitflags;
+use serde::Deserialize;
+use strum::{Display, EnumString};
 use thiserror::Error;
 
-use crate::{controller::Controller, controller_state::ControllerState};
+use crate::{
+    button_mapping::ButtonProfile,
+    controller::Controller,
+    controller_state::{ControllerState, STANDARD_REPORT_PERIOD},
+    stick_state::{NoCalibrationDataAvailable, StickDirection},
+};
+
+/// Frames a combo step lasts if it doesn't say otherwise, 50ms at 60Hz
+pub const DEFAULT_COMBO_STEP_FRAMES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display, Deserialize)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    Y,
+    X,
+    B,
+    A,
+    Sr,
+    Sl,
+    R,
+    Zr,
+    Minus,
+    Plus,
+    RStick,
+    LStick,
+    Home,
+    Capture,
+    Down,
+    Up,
+    Right,
+    Left,
+    L,
+    Zl,
+}
+
+bitflags! {
+    /* Button bits of the input report, byte n of the report is byte n of the little endian value
+     * https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering/blob/master/bluetooth_hid_notes.md
+    ┌─────┬──────┬─────┬────────┬────────┬─────┬────────┬───┬────┐
+    │Byte │ 0    │ 1   │ 2      │ 3      │ 4   │ 5      │ 6 │ 7  │
+    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
+    │     │      │     │        │        │     │        │   │    │
+    │   1 │ Y    │ X   │ B      │ A      │ SR  │ SL     │ R │ ZR │
+    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
+    │     │      │     │        │        │     │        │   │    │
+    │   2 │ Minus│ Plus│ R_Stick│ L_Stick│ Home│ Capture│   │    │
+    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
+    │     │      │     │        │        │     │        │   │    │
+    │   3 │ Down │ Up  │ Right  │ Left   │ SR  │ SL     │ L │ ZL │
+    └─────┴──────┴─────┴────────┴────────┴─────┴────────┴───┴────┘
+     */
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct ButtonFlags: u32 {
+        const Y = 1 << 7;
+        const X = 1 << 6;
+        const B = 1 << 5;
+        const A = 1 << 4;
+        /// SR of the Joy-Con (R)
+        const SR_R = 1 << 3;
+        /// SL of the Joy-Con (R)
+        const SL_R = 1 << 2;
+        const R = 1 << 1;
+        const ZR = 1 << 0;
+
+        const MINUS = 1 << 15;
+        const PLUS = 1 << 14;
+        const R_STICK = 1 << 13;
+        const L_STICK = 1 << 12;
+        const HOME = 1 << 11;
+        const CAPTURE = 1 << 10;
+
+        const DOWN = 1 << 23;
+        const UP = 1 << 22;
+        const RIGHT = 1 << 21;
+        const LEFT = 1 << 20;
+        /// SR of the Joy-Con (L)
+        const SR_L = 1 << 19;
+        /// SL of the Joy-Con (L)
+        const SL_L = 1 << 18;
+        const L = 1 << 17;
+        const ZL = 1 << 16;
+    }
+}
+
+impl Button {
+    /// Report bit of the button on `controller`, None if the controller doesn't have it
+    pub fn flag(&self, controller: Controller) -> Option<ButtonFlags> {
+        let available = match controller {
+            Controller::JoyconL => ButtonState::JOYCON_L_AVAILABLE_BUTTONS.contains(self),
+            Controller::JoyconR => ButtonState::JOYCON_R_AVAILABLE_BUTTONS.contains(self),
+            Controller::ProController => {
+                ButtonState::PRO_CONTROLLER_AVAILABLE_BUTTONS.contains(self)
+            }
+        };
+        if !available {
+            return None;
+        }
+        Some(match self {
+            Self::Y => ButtonFlags::Y,
+            Self::X => ButtonFlags::X,
+            Self::B => ButtonFlags::B,
+            Self::A => ButtonFlags::A,
+            Self::Sr if controller == Controller::JoyconR => ButtonFlags::SR_R,
+            Self::Sr => ButtonFlags::SR_L,
+            Self::Sl if controller == Controller::JoyconR => ButtonFlags::SL_R,
+            Self::Sl => ButtonFlags::SL_L,
+            Self::R => ButtonFlags::R,
+            Self::Zr => ButtonFlags::ZR,
+            Self::Minus => ButtonFlags::MINUS,
+            Self::Plus => ButtonFlags::PLUS,
+            Self::RStick => ButtonFlags::R_STICK,
+            Self::LStick => ButtonFlags::L_STICK,
+            Self::Home => ButtonFlags::HOME,
+            Self::Capture => ButtonFlags::CAPTURE,
+            Self::Down => ButtonFlags::DOWN,
+            Self::Up => ButtonFlags::UP,
+            Self::Right => ButtonFlags::RIGHT,
+            Self::Left => ButtonFlags::LEFT,
+            Self::L => ButtonFlags::L,
+            Self::Zl => ButtonFlags::ZL,
+        })
+    }
+}
+
+/// Button behaviour driven by the sent input reports, see `ButtonState::advance`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ButtonMode {
+    /// Alternates every `half_period_frames` input reports
+    Turbo {
+        half_period_frames: u32,
+    },
+    Hold {
+        duration: Duration,
+    },
+    /// Pressed in exactly `frames` input reports
+    HoldFrames {
+        frames: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct TimedButton {
+    flag: ButtonFlags,
+    mode: ButtonMode,
+    elapsed: Duration,
+    /// Number of reports since the mode started
+    frames: u32,
+}
 
 #[derive(Debug, Clone)]
 pub struct ButtonState {
     pub controller: Controller,
-    button_states: HashMap<String, bool>,
+    pressed: ButtonFlags,
+    profile: ButtonProfile,
+    /// Buttons with a turbo or hold running, usually only a few
+    timed: Vec<TimedButton>,
 }
 
 impl ButtonState {
-    const PRO_CONTROLLER_AVAILABLE_BUTTONS: [&'static str; 18] = [
-        "y", "x", "b", "a", "r", "zr", "minus", "plus", "r_stick", "l_stick", "home", "capture",
-        "down", "up", "right", "left", "l", "zl",
+    const PRO_CONTROLLER_AVAILABLE_BUTTONS: [Button; 18] = [
+        Button::Y,
+        Button::X,
+        Button::B,
+        Button::A,
+        Button::R,
+        Button::Zr,
+        Button::Minus,
+        Button::Plus,
+        Button::RStick,
+        Button::LStick,
+        Button::Home,
+        Button::Capture,
+        Button::Down,
+        Button::Up,
+        Button::Right,
+        Button::Left,
+        Button::L,
+        Button::Zl,
     ];
-    const JOYCON_R_AVAILABLE_BUTTONS: [&'static str; 11] = [
-        "y", "x", "b", "a", "sr", "sl", "r", "zr", "plus", "r_stick", "home",
+    const JOYCON_R_AVAILABLE_BUTTONS: [Button; 11] = [
+        Button::Y,
+        Button::X,
+        Button::B,
+        Button::A,
+        Button::Sr,
+        Button::Sl,
+        Button::R,
+        Button::Zr,
+        Button::Plus,
+        Button::RStick,
+        Button::Home,
     ];
-    const JOYCON_L_AVAILABLE_BUTTONS: [&'static str; 11] = [
-        "minus", "l_stick", "capture", "down", "up", "right", "left", "sr", "sl", "l", "zl",
+    const JOYCON_L_AVAILABLE_BUTTONS: [Button; 11] = [
+        Button::Minus,
+        Button::LStick,
+        Button::Capture,
+        Button::Down,
+        Button::Up,
+        Button::Right,
+        Button::Left,
+        Button::Sr,
+        Button::Sl,
+        Button::L,
+        Button::Zl,
     ];
 
     pub fn new(controller: Controller) -> Self {
-        let button_states = match controller {
-            Controller::JoyconL => Self::JOYCON_L_AVAILABLE_BUTTONS
-                .into_iter()
-                .map(Into::into)
-                .zip(repeat(false))
-                .collect(),
-            Controller::JoyconR => Self::JOYCON_R_AVAILABLE_BUTTONS
-                .into_iter()
-                .map(Into::into)
-                .zip(repeat(false))
-                .collect(),
-            Controller::ProController => Self::PRO_CONTROLLER_AVAILABLE_BUTTONS
-                .into_iter()
-                .map(Into::into)
-                .zip(repeat(false))
-                .collect(),
-        };
         Self {
             controller,
-            button_states,
+            pressed: ButtonFlags::empty(),
+            profile: ButtonProfile::default(),
+            timed: vec![],
         }
     }
 
-    pub fn set_button(&mut self, button: &str, pushed: bool) -> Result<(), ButtonStateError> {
-        let button = button.to_lowercase();
-        if !self.button_available(&button) {
-            return Err(ButtonStateError::ButtonNotAvailable(
-                button,
-                self.controller,
-            ));
-        }
-        self.button_states.insert(button, pushed);
+    /// The profile applies to every way of setting and getting buttons except the flags
+    #[inline]
+    pub fn set_profile(&mut self, profile: Option<ButtonProfile>) {
+        self.profile = profile.unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn get_profile(&self) -> &ButtonProfile {
+        &self.profile
+    }
+
+    /// Button named by the button or alias `name`. Remapping is applied when it is set, so this
+    /// can be passed to `set` and the mode functions.
+    pub fn resolve(&self, name: &str) -> Result<Button, ButtonStateError> {
+        let button = self
+            .profile
+            .resolve(name)
+            .ok_or_else(|| ButtonStateError::UnknownButton(name.into()))?;
+        self.mapped_flag(button)?;
+        Ok(button)
+    }
+
+    /// Setting a button also stops any mode running on it
+    pub fn set(&mut self, button: Button, pushed: bool) -> Result<(), ButtonStateError> {
+        let flag = self.mapped_flag(button)?;
+        self.stop_flag_mode(flag);
+        self.pressed.set(flag, pushed);
         Ok(())
     }
 
-    pub fn get_button(&self, button: &str) -> Result<bool, ButtonStateError> {
-        let button = button.to_lowercase();
-        if !self.button_available(&button) {
-            return Err(ButtonStateError::ButtonNotAvailable(
-                button,
-                self.controller,
-            ));
-        }
-        Ok(*self.button_states.get(&button).unwrap())
+    pub fn get(&self, button: Button) -> Result<bool, ButtonStateError> {
+        Ok(self.pressed.contains(self.mapped_flag(button)?))
     }
 
+    /// String version of `set`, button names and aliases are case insensitive
     #[inline]
-    fn button_available(&self, button: &str) -> bool {
-        match self.controller {
-            Controller::JoyconL => Self::JOYCON_L_AVAILABLE_BUTTONS.contains(&button),
-            Controller::JoyconR => Self::JOYCON_R_AVAILABLE_BUTTONS.contains(&button),
-            Controller::ProController => Self::PRO_CONTROLLER_AVAILABLE_BUTTONS.contains(&button),
-        }
+    pub fn set_button(&mut self, button: &str, pushed: bool) -> Result<(), ButtonStateError> {
+        self.set(self.resolve(button)?, pushed)
     }
 
+    /// String version of `get`
     #[inline]
-    pub fn get_available_buttons(&self) -> &[&'static str] {
-        match self.controller {
-            Controller::JoyconL => &Self::JOYCON_L_AVAILABLE_BUTTONS,
-            Controller::JoyconR => &Self::JOYCON_R_AVAILABLE_BUTTONS,
-            Controller::ProController => &Self::PRO_CONTROLLER_AVAILABLE_BUTTONS,
+    pub fn get_button(&self, button: &str) -> Result<bool, ButtonStateError> {
+        self.get(self.resolve(button)?)
+    }
+
+    /// Repeatedly presses and releases `button` `frequency` times per second at the standard
+    /// report rate, starting pressed. The button changes at most once per report, so the frequency
+    /// is rounded to a whole number of reports and limited to half the report rate.
+    pub fn set_turbo(&mut self, button: Button, frequency: f32) -> Result<(), ButtonStateError> {
+        if !(frequency.is_finite() && frequency > 0.0) {
+            return Err(ButtonStateError::InvalidTurboFrequency(frequency));
         }
+        let half_period_frames = (0.5 / (frequency * STANDARD_REPORT_PERIOD.as_secs_f32()))
+            .round()
+            .clamp(1.0, u32::MAX as f32) as u32;
+        self.start_mode(button, ButtonMode::Turbo { half_period_frames })
     }
 
-    #[inline]
-    pub fn clear(&mut self) {
-        self.button_states.iter_mut().for_each(|(_, v)| *v = false)
+    /// Presses `button` and releases it after `duration`, independent of other input. The button is
+    /// pressed in at least one input report.
+    pub fn set_hold(&mut self, button: Button, duration: Duration) -> Result<(), ButtonStateError> {
+        self.start_mode(button, ButtonMode::Hold { duration })
     }
 
-    fn bitmask_button_state(&self, button: &str, byte: &mut u8, mask: u8) {
-        if *self.button_states.get(button).unwrap() {
-            *byte |= mask
+    /// Presses `button` for exactly `frames` input reports, counting from the next one. 0 frames
+    /// leaves it released.
+    pub fn set_hold_frames(&mut self, button: Button, frames: u32) -> Result<(), ButtonStateError> {
+        if frames == 0 {
+            return self.set(button, false);
         }
+        self.start_mode(button, ButtonMode::HoldFrames { frames })
     }
 
-    /* Utility func to set buttons in the input report
-     * https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering/blob/master/bluetooth_hid_notes.md
-    ┌─────┬──────┬─────┬────────┬────────┬─────┬────────┬───┬────┐
-    │Byte │ 0    │ 1   │ 2      │ 3      │ 4   │ 5      │ 6 │ 7  │
-    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
-    │     │      │     │        │        │     │        │   │    │
-    │   1 │ Y    │ X   │ B      │ A      │ SR  │ SL     │ R │ ZR │
-    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
-    │     │      │     │        │        │     │        │   │    │
-    │   2 │ Minus│ Plus│ R_Stick│ L_Stick│ Home│ Capture│   │    │
-    ├─────┼──────┼─────┼────────┼────────┼─────┼────────┼───┼────┤
-    │     │      │     │        │        │     │        │   │    │
-    │   3 │ Down │ Up  │ Right  │ Left   │ SR  │ SL     │ L │ ZL │
-    └─────┴──────┴─────┴────────┴────────┴─────┴────────┴───┴────┘
-     */
-
-    pub fn as_bytes(&self) -> [u8; 3] {
-        let mut result = [0, 0, 0];
-        match self.controller {
-            Controller::ProController => {
-                // byte 0
-                self.bitmask_button_state("y", &mut result[0], 0b10000000);
-                self.bitmask_button_state("x", &mut result[0], 0b01000000);
-                self.bitmask_button_state("b", &mut result[0], 0b00100000);
-                self.bitmask_button_state("a", &mut result[0], 0b00010000);
-
-                self.bitmask_button_state("r", &mut result[0], 0b00000010);
-                self.bitmask_button_state("zr", &mut result[0], 0b00000001);
-
-                // byte 1
-                self.bitmask_button_state("minus", &mut result[1], 0b10000000);
-                self.bitmask_button_state("plus", &mut result[1], 0b01000000);
-                self.bitmask_button_state("r_stick", &mut result[1], 0b00100000);
-                self.bitmask_button_state("l_stick", &mut result[1], 0b00010000);
-
-                self.bitmask_button_state("home", &mut result[1], 0b00001000);
-                self.bitmask_button_state("capture", &mut result[1], 0b00000100);
-
-                // byte 2
-                self.bitmask_button_state("down", &mut result[2], 0b10000000);
-                self.bitmask_button_state("up", &mut result[2], 0b01000000);
-                self.bitmask_button_state("right", &mut result[2], 0b00100000);
-                self.bitmask_button_state("left", &mut result[2], 0b00010000);
-
-                self.bitmask_button_state("l", &mut result[2], 0b00000010);
-                self.bitmask_button_state("zl", &mut result[2], 0b00000001);
-            }
-            Controller::JoyconR => {
-                // byte 0
-                self.bitmask_button_state("y", &mut result[0], 0b10000000);
-                self.bitmask_button_state("x", &mut result[0], 0b01000000);
-                self.bitmask_button_state("b", &mut result[0], 0b00100000);
-                self.bitmask_button_state("a", &mut result[0], 0b00010000);
+    /// Latches `button` in the opposite state, returns whether it is pressed now
+    pub fn toggle(&mut self, button: Button) -> Result<bool, ButtonStateError> {
+        let pressed = !self.get(button)?;
+        self.set(button, pressed)?;
+        Ok(pressed)
+    }
 
-                self.bitmask_button_state("sr", &mut result[0], 0b00001000);
-                self.bitmask_button_state("sl", &mut result[0], 0b00000100);
+    /// Stops the turbo or hold on `button` and releases it
+    pub fn stop_mode(&mut self, button: Button) -> Result<(), ButtonStateError> {
+        self.set(button, false)
+    }
 
-                self.bitmask_button_state("r", &mut result[0], 0b00000010);
-                self.bitmask_button_state("zr", &mut result[0], 0b00000001);
+    #[inline]
+    pub fn get_mode(&self, button: Button) -> Option<ButtonMode> {
+        let flag = self.mapped_flag(button).ok()?;
+        self.timed
+            .iter()
+            .find(|timed| timed.flag == flag)
+            .map(|timed| timed.mode)
+    }
 
-                // byte 1
-                self.bitmask_button_state("minus", &mut result[1], 0b10000000);
-                self.bitmask_button_state("plus", &mut result[1], 0b01000000);
-                self.bitmask_button_state("r_stick", &mut result[1], 0b00100000);
-                self.bitmask_button_state("l_stick", &mut result[1], 0b00010000);
+    fn start_mode(&mut self, button: Button, mode: ButtonMode) -> Result<(), ButtonStateError> {
+        let flag = self.mapped_flag(button)?;
+        self.stop_flag_mode(flag);
+        self.pressed.insert(flag);
+        self.timed.push(TimedButton {
+            flag,
+            mode,
+            elapsed: Duration::ZERO,
+            frames: 0,
+        });
+        Ok(())
+    }
 
-                self.bitmask_button_state("home", &mut result[1], 0b00001000);
+    #[inline]
+    fn stop_flag_mode(&mut self, flag: ButtonFlags) {
+        self.timed.retain(|timed| timed.flag != flag)
+    }
 
-                // byte 2
-                // Nothing for Joycon R
+    /// Called by `ControllerState` before every input report with the time since the previous one.
+    /// Updates turbo buttons and releases expired holds.
+    pub fn advance(&mut self, elapsed: Duration) {
+        let pressed = &mut self.pressed;
+        self.timed.retain_mut(|timed| {
+            timed.elapsed += elapsed;
+            timed.frames += 1;
+            match timed.mode {
+                ButtonMode::Turbo { half_period_frames } => {
+                    let phase = (timed.frames - 1) / half_period_frames;
+                    pressed.set(timed.flag, phase % 2 == 0);
+                    true
+                }
+                ButtonMode::Hold { duration } => {
+                    // Holds shorter than a report period still make it into one report
+                    let running = timed.frames == 1 || timed.elapsed < duration;
+                    pressed.set(timed.flag, running);
+                    running
+                }
+                ButtonMode::HoldFrames { frames } => {
+                    let running = timed.frames <= frames;
+                    pressed.set(timed.flag, running);
+                    running
+                }
             }
-            Controller::JoyconL => {
-                // byte 0
-                // Nothing for Joycon L
+        });
+    }
 
-                // byte 1
-                self.bitmask_button_state("minus", &mut result[1], 0b10000000);
-                self.bitmask_button_state("plus", &mut result[1], 0b01000000);
-                self.bitmask_button_state("r_stick", &mut result[1], 0b00100000);
-                self.bitmask_button_state("l_stick", &mut result[1], 0b00010000);
+    /// Flag of `button` after remapping
+    #[inline]
+    fn mapped_flag(&self, button: Button) -> Result<ButtonFlags, ButtonStateError> {
+        self.flag(self.profile.map(button))
+    }
 
-                self.bitmask_button_state("capture", &mut result[1], 0b00000100);
+    #[inline]
+    fn flag(&self, button: Button) -> Result<ButtonFlags, ButtonStateError> {
+        button
+            .flag(self.controller)
+            .ok_or(ButtonStateError::ButtonNotAvailable(
+                button,
+                self.controller,
+            ))
+    }
 
-                // byte 2
-                self.bitmask_button_state("down", &mut result[2], 0b10000000);
-                self.bitmask_button_state("up", &mut result[2], 0b01000000);
-                self.bitmask_button_state("right", &mut result[2], 0b00100000);
-                self.bitmask_button_state("left", &mut result[2], 0b00010000);
+    #[inline]
+    pub fn button_available(&self, button: Button) -> bool {
+        self.get_available_buttons().contains(&button)
+    }
 
-                self.bitmask_button_state("sr", &mut result[2], 0b00001000);
-                self.bitmask_button_state("sl", &mut result[2], 0b00000100);
+    #[inline]
+    pub fn get_available_buttons(&self) -> &'static [Button] {
+        match self.controller {
+            Controller::JoyconL => &Self::JOYCON_L_AVAILABLE_BUTTONS,
+            Controller::JoyconR => &Self::JOYCON_R_AVAILABLE_BUTTONS,
+            Controller::ProController => &Self::PRO_CONTROLLER_AVAILABLE_BUTTONS,
+        }
+    }
 
-                self.bitmask_button_state("l", &mut result[2], 0b00000010);
-                self.bitmask_button_state("zl", &mut result[2], 0b00000001);
-            }
-        };
-        result
+    /// All pressed buttons at once
+    #[inline]
+    pub fn get_flags(&self) -> ButtonFlags {
+        self.pressed
+    }
+
+    /// Bits of buttons the controller doesn't have are dropped
+    #[inline]
+    pub fn set_flags(&mut self, flags: ButtonFlags) {
+        let available = self
+            .get_available_buttons()
+            .iter()
+            .filter_map(|button| button.flag(self.controller))
+            .collect::<ButtonFlags>();
+        self.pressed = flags & available
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.pressed = ButtonFlags::empty();
+        self.timed.clear();
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> [u8; 3] {
+        let [byte_0, byte_1, byte_2, _] = self.pressed.bits().to_le_bytes();
+        [byte_0, byte_1, byte_2]
     }
 }
 
@@ -243,15 +466,291 @@ pub async fn button_push(
     sec: Option<f32>,
 ) -> Result<(), ButtonStateError> {
     button_press(controller_state, buttons).await?;
-    tokio::time::sleep(Duration::from_secs_f32(sec.unwrap_or(0.1))).await;
+    controller_state
+        .sleep(Duration::from_secs_f32(sec.unwrap_or(0.1)))
+        .await;
     button_release(controller_state, buttons).await?;
     Ok(())
 }
 
+/// Unlike `button_push`, the buttons are guaranteed to be pressed in exactly `frames` sent input
+/// reports. Returns once the report releasing them was sent.
+pub async fn button_push_frames(
+    controller_state: &mut ControllerState,
+    buttons: &[String],
+    frames: u32,
+) -> Result<(), ButtonStateError> {
+    if buttons.is_empty() {
+        return Err(ButtonStateError::NoButtonsGiven);
+    }
+    let button_state = &mut controller_state.button_state;
+    // Resolving everything first so that either all or no buttons are pressed
+    let buttons = buttons
+        .iter()
+        .map(|button| button_state.resolve(button))
+        .collect::<Result<Vec<_>, _>>()?;
+    for button in buttons {
+        button_state.set_hold_frames(button, frames)?;
+    }
+
+    controller_state.wait_for_reports(frames + 1).await;
+
+    Ok(())
+}
+
+/// Part of a combo step, stick positions are normalized (see `StickState::set_normalized`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComboInput {
+    Button(Button),
+    LeftStick(f32, f32),
+    RightStick(f32, f32),
+}
+
+impl ComboInput {
+    /// `direction` is a `StickDirection` without a value or an angle in degrees
+    fn parse_stick(direction: &str) -> Result<(f32, f32), ComboError> {
+        if let Ok(angle) = direction.parse::<f32>() {
+            let angle = angle.to_radians();
+            return Ok((angle.cos(), angle.sin()));
+        }
+        let angle = |degrees: f32| (degrees.to_radians().cos(), degrees.to_radians().sin());
+        match StickDirection::from_str(direction) {
+            Ok(StickDirection::Center) => Ok((0.0, 0.0)),
+            Ok(StickDirection::Up) => Ok((0.0, 1.0)),
+            Ok(StickDirection::Down) => Ok((0.0, -1.0)),
+            Ok(StickDirection::Left) => Ok((-1.0, 0.0)),
+            Ok(StickDirection::Right) => Ok((1.0, 0.0)),
+            Ok(StickDirection::UpRight) => Ok(angle(45.0)),
+            Ok(StickDirection::UpLeft) => Ok(angle(135.0)),
+            Ok(StickDirection::DownLeft) => Ok(angle(225.0)),
+            Ok(StickDirection::DownRight) => Ok(angle(315.0)),
+            _ => Err(ComboError::InvalidStickDirection(direction.into())),
+        }
+    }
+}
+
+/// Inputs held together for `frames` input reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComboStep {
+    pub inputs: Vec<ComboInput>,
+    pub frames: u32,
+}
+
+/// Chords and sequences of button presses and stick positions. Steps are separated by spaces,
+/// inputs pressed together by '+' and a step lasts `DEFAULT_COMBO_STEP_FRAMES` unless followed by
+/// ':' and a number of frames. Sticks are 'ls.' or 'rs.' and a direction or an angle in degrees,
+/// '_' is a step without input. Buttons are released after their step, sticks stay in place until
+/// the end of the combo. Examples:
+/// - `l+r` presses L and R in the same report
+/// - `ls.down ls.down_right ls.right+a:2` is a quarter circle forward followed by A
+/// - `a:1 _:1 a:1` presses A twice with one report in between
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combo {
+    pub steps: Vec<ComboStep>,
+}
+
+impl Combo {
+    /// Buttons are resolved with the profile of `button_state`, so aliases can be used
+    pub fn parse(combo: &str, button_state: &ButtonState) -> Result<Self, ComboError> {
+        let steps = combo
+            .split_whitespace()
+            .map(|step| {
+                let (chord, frames) = match step.split_once(':') {
+                    Some((chord, frames)) => (
+                        chord,
+                        frames
+                            .parse()
+                            .map_err(|_| ComboError::InvalidFrames(frames.into()))?,
+                    ),
+                    None => (step, DEFAULT_COMBO_STEP_FRAMES),
+                };
+                let inputs = if chord == "_" {
+                    vec![]
+                } else {
+                    chord
+                        .split('+')
+                        .map(|input| match input.split_once('.') {
+                            Some(("ls", direction)) => ComboInput::parse_stick(direction)
+                                .map(|(x, y)| ComboInput::LeftStick(x, y)),
+                            Some(("rs", direction)) => ComboInput::parse_stick(direction)
+                                .map(|(x, y)| ComboInput::RightStick(x, y)),
+                            _ => Ok(ComboInput::Button(button_state.resolve(input)?)),
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+                Ok(ComboStep { inputs, frames })
+            })
+            .collect::<Result<Vec<_>, ComboError>>()?;
+        if steps.is_empty() {
+            Err(ComboError::EmptyCombo)
+        } else {
+            Ok(Self { steps })
+        }
+    }
+}
+
+/// Plays `combo` step by step, every step is visible in exactly its number of sent input reports:
+/// buttons are held with `ButtonState::set_hold_frames`, so they are released by the report
+/// counter even if the combo is interrupted. Returns once the report releasing everything was
+/// sent.
+pub async fn run_combo(
+    controller_state: &mut ControllerState,
+    combo: &Combo,
+) -> Result<(), ComboError> {
+    let (mut l_stick_moved, mut r_stick_moved) = (false, false);
+    for step in &combo.steps {
+        for input in &step.inputs {
+            match *input {
+                ComboInput::Button(button) => controller_state
+                    .button_state
+                    .set_hold_frames(button, step.frames)?,
+                ComboInput::LeftStick(x, y) => {
+                    let stick = controller_state
+                        .l_stick_state
+                        .as_mut()
+                        .ok_or(ComboError::StickNotAvailable("left"))?;
+                    stick.set_normalized(x, y)?;
+                    l_stick_moved = true;
+                }
+                ComboInput::RightStick(x, y) => {
+                    let stick = controller_state
+                        .r_stick_state
+                        .as_mut()
+                        .ok_or(ComboError::StickNotAvailable("right"))?;
+                    stick.set_normalized(x, y)?;
+                    r_stick_moved = true;
+                }
+            }
+        }
+
+        controller_state.wait_for_reports(step.frames).await;
+    }
+
+    if l_stick_moved {
+        if let Some(stick) = controller_state.l_stick_state.as_mut() {
+            stick.set_center()?;
+        }
+    }
+    if r_stick_moved {
+        if let Some(stick) = controller_state.r_stick_state.as_mut() {
+            stick.set_center()?;
+        }
+    }
+    controller_state.wait_for_reports(1).await;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum ComboError {
+    #[error("The combo has no steps.")]
+    EmptyCombo,
+    #[error("Invalid number of frames \"{0}\".")]
+    InvalidFrames(String),
+    #[error("Invalid stick direction \"{0}\".")]
+    InvalidStickDirection(String),
+    #[error("The controller has no {0} stick.")]
+    StickNotAvailable(&'static str),
+    #[error(transparent)]
+    Button(#[from] ButtonStateError),
+    #[error(transparent)]
+    Stick(#[from] NoCalibrationDataAvailable),
+}
+
 #[derive(Debug, Clone, Error)]
 pub enum ButtonStateError {
     #[error("No buttons were given.")]
     NoButtonsGiven,
+    #[error("Unknown button \"{0}\".")]
+    UnknownButton(String),
     #[error("Given button \"{0}\" is not available to {1}.")]
-    ButtonNotAvailable(String, Controller),
+    ButtonNotAvailable(Button, Controller),
+    #[error("Turbo frequency must be positive, got {0}.")]
+    InvalidTurboFrequency(f32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stick_state::StickState, transport::connected_state};
+
+    #[test]
+    fn turbo_alternates_per_report() {
+        let mut button_state = ButtonState::new(Controller::ProController);
+        button_state.set_turbo(Button::A, 15.0).unwrap();
+        assert_eq!(
+            button_state.get_mode(Button::A),
+            Some(ButtonMode::Turbo {
+                half_period_frames: 2
+            })
+        );
+
+        // Report timing doesn't matter, only the number of reports
+        let pressed = [1, 40, 3, 16, 100, 0, 16, 16]
+            .into_iter()
+            .map(|millis| {
+                button_state.advance(Duration::from_millis(millis));
+                button_state.get(Button::A).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pressed,
+            [true, true, false, false, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn short_holds_are_in_one_report() {
+        let mut button_state = ButtonState::new(Controller::ProController);
+        button_state
+            .set_hold(Button::A, Duration::from_millis(1))
+            .unwrap();
+        let pressed = (0..3)
+            .map(|_| {
+                button_state.advance(Duration::from_millis(16));
+                button_state.get(Button::A).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pressed, [true, false, false]);
+
+        button_state
+            .set_hold(Button::A, Duration::from_millis(40))
+            .unwrap();
+        let pressed = (0..4)
+            .map(|_| {
+                button_state.advance(Duration::from_millis(16));
+                button_state.get(Button::A).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pressed, [true, true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn combo_steps_last_their_number_of_reports() {
+        let (mut controller_state, transport) = connected_state();
+        let combo =
+            Combo::parse("a:2 _:1 a+b:1 rs.right:2", &controller_state.button_state).unwrap();
+        run_combo(&mut controller_state, &combo).await.unwrap();
+
+        let reports = transport.take_reports();
+        let inputs = reports
+            .iter()
+            .map(|report| {
+                let right_stick = StickState::from(&report[10..13].try_into().unwrap());
+                (report[4] & 0x30, right_stick.get_h() > 0x800 + 0x100)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs,
+            [
+                (0x10, false),
+                (0x10, false),
+                (0x00, false),
+                (0x30, false),
+                (0x00, true),
+                (0x00, true),
+                (0x00, false),
+            ]
+        );
+    }
 }
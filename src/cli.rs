@@ -1,59 +1,460 @@
-use std::{future::Future, pin::Pin};
+use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
 
 use hashbrown::HashMap;
-use tokio::sync::mpsc::{channel, Receiver};
+use thiserror::Error;
+use tokio::sync::Mutex;
 
 use crate::{
+    amiibo_library::AmiiboLibrary,
+    button_mapping::{ButtonProfiles, ProfileNotFound},
+    button_state::{
+        button_press, button_push, button_release, run_combo, ButtonStateError, Combo, ComboError,
+    },
     controller_state::ControllerState,
-    stick_state::{InvalidStickValue, StickDirection, StickState}, button_state::button_push,
+    ir_camera::{IrFrameSource, IrImage},
+    line_editor::{default_history_file, CompletionWords, LineEditor},
+    rhai_script::{RhaiScript, RhaiScriptError, ScriptMessage, ScriptRequest},
+    script::Script,
+    stick_motion::StickMotion,
+    stick_state::{InvalidStickValue, NoCalibrationDataAvailable, StickDirection, StickState},
 };
 
-const STICK_CMD_DOC: &'static str = 
-            "stick - command to set stick positions\n\
-            :param side: 'l', 'left' for left control stick; 'r', 'right' for right control stick\n\
-            :param direction: 'center', 'up', 'down', 'left', 'right';\n\
-                              'h', 'horizontal' or 'v', 'vertical' to set the value directly to the \"value\" argument\n\
-            :param value: horizontal or vertical value";
+/// Output of a command, printed by the CLI
+pub type CommandFuture<'b> = Pin<Box<dyn Future<Output = Result<String, CliError>> + 'b>>;
+type CommandFn = Box<dyn for<'b> Fn(&'b mut ControllerState, Vec<String>) -> CommandFuture<'b>>;
+
+/// The built-in commands need the CLI itself instead of only the controller state
+enum CommandHandler {
+    Function(CommandFn),
+    Help,
+    Exit,
+    Source,
+    Rhai,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandArg {
+    pub name: String,
+    pub help: String,
+    pub optional: bool,
+}
+
+/// A command of the CLI. Arguments are only used for the help text and to check that the
+/// required ones were given, the handler gets all of them, also extra ones.
+pub struct CliCommand {
+    name: String,
+    help: String,
+    args: Vec<CommandArg>,
+    handler: CommandHandler,
+}
+
+impl CliCommand {
+    pub fn new<F>(name: &str, help: &str, handler: F) -> Self
+    where
+        F: for<'b> Fn(&'b mut ControllerState, Vec<String>) -> CommandFuture<'b> + 'static,
+    {
+        Self {
+            name: name.into(),
+            help: help.into(),
+            args: vec![],
+            handler: CommandHandler::Function(Box::new(handler)),
+        }
+    }
+
+    fn builtin(name: &str, help: &str, handler: CommandHandler) -> Self {
+        Self {
+            name: name.into(),
+            help: help.into(),
+            args: vec![],
+            handler,
+        }
+    }
+
+    /// Required arguments have to come before optional ones
+    pub fn arg(mut self, name: &str, help: &str) -> Self {
+        self.args.push(CommandArg {
+            name: name.into(),
+            help: help.into(),
+            optional: false,
+        });
+        self
+    }
+
+    pub fn optional_arg(mut self, name: &str, help: &str) -> Self {
+        self.args.push(CommandArg {
+            name: name.into(),
+            help: help.into(),
+            optional: true,
+        });
+        self
+    }
+
+    #[inline]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Usage line followed by the arguments, one per line
+    pub fn help_text(&self) -> String {
+        let mut result = self.name.clone();
+        for arg in &self.args {
+            if arg.optional {
+                result += &format!(" [{}]", arg.name);
+            } else {
+                result += &format!(" <{}>", arg.name);
+            }
+        }
+        result += &format!(" - {}", self.help);
+        for arg in &self.args {
+            let prefix = format!("    :param {}: ", arg.name);
+            let indent = " ".repeat(prefix.len());
+            result += &format!(
+                "\n{}{}",
+                prefix,
+                arg.help.replace('\n', &format!("\n{}", indent))
+            );
+        }
+        result
+    }
+
+    fn missing_arg(&self, given: usize) -> Option<&CommandArg> {
+        self.args.iter().filter(|arg| !arg.optional).nth(given)
+    }
+}
 
 pub struct ControllerCli<'a> {
-    rx: Receiver<String>,
     controller_state: &'a mut ControllerState,
+    commands: HashMap<String, CliCommand>,
+    /// Started on the first read, so the history file can still be changed before
+    editor: Option<LineEditor>,
+    history_file: Option<PathBuf>,
+    completions: Arc<std::sync::Mutex<CompletionWords>>,
+    /// Served while waiting for input
+    rhai_script: Option<RhaiScript>,
+    /// Set by the exit command
+    exit_requested: bool,
 }
 
-// TODO: other commands, mostly test, from run_controller_cli.py @
-// _register_commands_with_controller_state, line 168
 impl<'a> ControllerCli<'a> {
     pub fn new(controller_state: &'a mut ControllerState) -> Self {
-        let (tx, rx) = channel(32); // I don't know what number will work the best
-        let stdin = std::io::stdin();
-        tokio::spawn(async move {
-            loop {
-                let mut buf = String::new();
-                stdin.read_line(&mut buf).unwrap();
-                tx.send(buf).await.unwrap();
-            }
-        });
-        Self {
-            rx,
+        let mut cli = Self {
             controller_state,
+            commands: HashMap::new(),
+            editor: None,
+            history_file: default_history_file(),
+            completions: Arc::new(std::sync::Mutex::new(CompletionWords::default())),
+            rhai_script: None,
+            exit_requested: false,
+        };
+        cli.register_default_commands();
+        cli
+    }
+
+    /// Replaces the command with the same name, if any, the built-in ones included. Button names
+    /// are only used if there is no command with that name.
+    pub fn register(&mut self, command: CliCommand) {
+        self.commands.insert(command.name.clone(), command);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<CliCommand> {
+        self.commands.remove(name)
+    }
+
+    #[inline]
+    pub fn has_command(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    #[inline]
+    pub fn get_controller_state_mut(&mut self) -> &mut ControllerState {
+        self.controller_state
+    }
+
+    pub fn set_amiibo_library(&mut self, amiibo_library: AmiiboLibrary) {
+        self.completions.lock().unwrap().amiibos = amiibo_library
+            .entries()
+            .iter()
+            .map(|entry| entry.name.clone())
+            .collect();
+        self.register(Self::nfc_command(Some(Arc::new(Mutex::new(
+            amiibo_library,
+        )))))
+    }
+
+    pub fn set_button_profiles(&mut self, button_profiles: ButtonProfiles) {
+        self.completions.lock().unwrap().profiles = button_profiles
+            .names()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        self.register(Self::profile_command(Some(Arc::new(button_profiles))))
+    }
+
+    /// Defaults to `HISTORY_FILE_NAME` in the home directory, None disables saving the history.
+    /// Only has an effect before the CLI reads its first line.
+    #[inline]
+    pub fn set_history_file(&mut self, history_file: Option<PathBuf>) {
+        self.history_file = history_file
+    }
+
+    /// Ported from run_controller_cli.py @ _register_commands_with_controller_state
+    fn register_default_commands(&mut self) {
+        self.register(
+            CliCommand::builtin("help", "command to show the help", CommandHandler::Help)
+                .optional_arg(
+                    "command",
+                    "command to show the help of, all commands by default",
+                ),
+        );
+        self.register(CliCommand::builtin(
+            "exit",
+            "command to close the CLI",
+            CommandHandler::Exit,
+        ));
+        self.register(
+            CliCommand::builtin("source", "command to run a script", CommandHandler::Source).arg(
+                "file",
+                "script with one command per line, see the script module",
+            ),
+        );
+        self.register(
+            CliCommand::builtin(
+                "rhai",
+                "command to start a Rhai script in the background",
+                CommandHandler::Rhai,
+            )
+            .arg("file", "Rhai script to run; 'stop' to stop the running one"),
+        );
+        self.register(
+            CliCommand::new("stick", "command to set stick positions", |controller_state, args| {
+                Box::pin(async move { Self::cmd_stick(controller_state, &as_strs(&args)).await })
+            })
+            .arg("side", "'l', 'left' for left control stick; 'r', 'right' for right control stick")
+            .arg(
+                "direction",
+                "'center', 'up', 'down', 'left', 'right', 'up_left', 'up_right', 'down_left', 'down_right';\n\
+                'h', 'horizontal' or 'v', 'vertical' to set the value directly to the \"value\" argument;\n\
+                'a', 'angle' to tilt the stick by \"value\" degrees counterclockwise from right;\n\
+                'p', 'percent' to tilt the stick by \"value\" percent horizontally and \"value2\" percent vertically",
+            )
+            .optional_arg("value", "horizontal or vertical value; angle in degrees; horizontal percentage")
+            .optional_arg("value2", "magnitude in [0, 1] for angles, defaults to 1; vertical percentage"),
+        );
+        self.register(
+            CliCommand::new("motion", "command to move a stick over time", |controller_state, args| {
+                Box::pin(async move { Self::cmd_motion(controller_state, &as_strs(&args)) })
+            })
+            .arg("side", "'l', 'left' or 'r', 'right' for the stick; 'stop' to stop the motions of both sticks")
+            .optional_arg(
+                "kind",
+                "'ramp' to move to \"value\" percent horizontally and \"value2\" percent vertically;\n\
+                'circle' to spin the stick \"value\" turns counterclockwise, negative turns spin clockwise;\n\
+                'flick' to push the stick out at \"value\" degrees and let it snap back",
+            )
+            .optional_arg("seconds", "duration of the motion")
+            .optional_arg("value", "horizontal percentage; turns; angle in degrees")
+            .optional_arg(
+                "value2",
+                "vertical percentage; magnitude in [0, 1] for circles, defaults to 1",
+            )
+            .optional_arg(
+                "easing",
+                "'linear', 'ease_in', 'ease_out' or 'ease_in_out' for ramps, defaults to 'linear'",
+            ),
+        );
+        self.register(Self::nfc_command(None));
+        self.register(
+            CliCommand::new(
+                "ir",
+                "command to set the images seen by the IR camera",
+                |controller_state, args| {
+                    Box::pin(async move { Self::cmd_ir(controller_state, &as_strs(&args)).await })
+                },
+            )
+            .arg(
+                "images",
+                "PNG or PGM files shown one per frame, separated by spaces;\n\
+                'pattern' for the moving test pattern",
+            ),
+        );
+        self.register(Self::profile_command(None));
+        for (mode, help, value_help) in [
+            (
+                "turbo",
+                "command to repeatedly press a button",
+                "presses per second; 'off' to stop it",
+            ),
+            (
+                "hold",
+                "command to hold a button for some time",
+                "seconds, or input reports with an 'f' suffix (e.g. '3f')",
+            ),
+        ] {
+            self.register(
+                CliCommand::new(mode, help, move |controller_state, args| {
+                    Box::pin(async move {
+                        Self::cmd_button_mode(controller_state, mode, &as_strs(&args))
+                    })
+                })
+                .arg("button", "button name or alias")
+                .arg("value", value_help),
+            );
         }
+        self.register(
+            CliCommand::new(
+                "toggle",
+                "command to latch a button pressed or released",
+                |controller_state, args| {
+                    Box::pin(async move {
+                        Self::cmd_button_mode(controller_state, "toggle", &as_strs(&args))
+                    })
+                },
+            )
+            .arg("button", "button name or alias"),
+        );
+        self.register(
+            CliCommand::new("combo", "command to play chords and sequences of inputs", |controller_state, args| {
+                Box::pin(async move {
+                    let combo = Combo::parse(&args.join(" "), &controller_state.button_state)?;
+                    run_combo(controller_state, &combo).await?;
+                    Ok("Combo done".into())
+                })
+            })
+            .arg(
+                "steps",
+                "steps separated by spaces, inputs pressed together joined by '+', e.g. 'l+r' or\n\
+                'ls.down ls.down_right ls.right+a:2'; ':n' makes a step last n reports (default 3),\n\
+                'ls.'/'rs.' followed by a direction or an angle move a stick, '_' is a step without input",
+            ),
+        );
+        self.register(
+            CliCommand::new(
+                "history",
+                "command to show the inputs that were sent",
+                |controller_state, args| {
+                    Box::pin(async move {
+                        Self::cmd_history(controller_state, args.first().map(|x| x.as_ref()))
+                    })
+                },
+            )
+            .optional_arg(
+                "action",
+                "number of most recent changes to show, defaults to 20;\n\
+                'stats' to show press counts and hold durations; 'clear' to reset the history",
+            ),
+        );
     }
 
+    fn nfc_command(amiibo_library: Option<Arc<Mutex<AmiiboLibrary>>>) -> CliCommand {
+        CliCommand::new(
+            "nfc",
+            "command to manage the amiibo library and the NFC tag",
+            move |controller_state, args| {
+                let amiibo_library = amiibo_library.clone();
+                Box::pin(async move {
+                    match amiibo_library {
+                        Some(library) => {
+                            Self::cmd_nfc(
+                                controller_state,
+                                Some(&mut *library.lock().await),
+                                &as_strs(&args),
+                            )
+                            .await
+                        }
+                        None => Self::cmd_nfc(controller_state, None, &as_strs(&args)).await,
+                    }
+                })
+            },
+        )
+        .optional_arg(
+            "action",
+            "'list' to show the amiibo library;\n\
+            'load' to put the amiibo matching \"name\" on the controller;\n\
+            'remove' to take the current tag away;\n\
+            'cycle' to load the next amiibo in the library;\n\
+            'restore' to put backup generation \"name\" of the current tag back, 0 being the newest",
+        )
+        .optional_arg(
+            "name",
+            "file name, UID, character ID or nickname of the amiibo; backup generation",
+        )
+    }
+
+    fn profile_command(button_profiles: Option<Arc<ButtonProfiles>>) -> CliCommand {
+        CliCommand::new(
+            "profile",
+            "command to select the button remapping profile",
+            move |controller_state, args| {
+                let button_profiles = button_profiles.clone();
+                Box::pin(async move {
+                    Self::cmd_profile(
+                        controller_state,
+                        button_profiles.as_deref(),
+                        args.first().map(|x| x.as_ref()),
+                    )
+                })
+            },
+        )
+        .optional_arg(
+            "name",
+            "name of the profile; 'off' to remove remapping; lists the profiles if omitted",
+        )
+    }
+
+    /// Keeps sending input reports and serves the running Rhai script until a line is entered
     async fn read_input_line(&mut self) -> String {
-        print!("cmd >> ");
-        self.rx.recv().await.unwrap()
+        self.update_completions();
+        loop {
+            let editor = self.editor.get_or_insert_with(|| {
+                LineEditor::spawn(self.completions.clone(), self.history_file.clone())
+            });
+            let rhai_script = &mut self.rhai_script;
+            let script_message = async move {
+                match rhai_script {
+                    Some(script) => script.next_message().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let message = tokio::select! {
+                line = editor.read_line() => return line.unwrap_or_else(|| "exit".into()),
+                message = script_message => message,
+                _ = self.controller_state.send() => continue,
+            };
+            if let Some(result) = self.serve_script_message(message).await {
+                let name = self
+                    .rhai_script
+                    .take()
+                    .map(|script| script.get_name().to_string());
+                match result {
+                    Ok(()) => println!("Script {} finished", name.unwrap_or_default()),
+                    Err(why) => println!("{}: {}", name.unwrap_or_default(), why),
+                }
+            }
+        }
     }
 
-    /// Implementing this as a regular command isn't possible because it requires access to &self
-    /// for iterating over the commands.
-    async fn regular_help(&self) {
-        println!("Commands:");
-        println!("{}", STICK_CMD_DOC);
-        println!("Commands can be chained using \"&&\"");
-        println!("Type \"exit\" to close.");
+    /// Commands and buttons change at runtime, e.g. with the button profile
+    fn update_completions(&self) {
+        let button_state = &self.controller_state.button_state;
+        let mut completions = self.completions.lock().unwrap();
+        completions.commands = self.commands.keys().cloned().collect();
+        completions.buttons = button_state
+            .get_available_buttons()
+            .iter()
+            .map(ToString::to_string)
+            .chain(button_state.get_profile().aliases.keys().cloned())
+            .collect();
     }
 
-    async fn help(&self) {
+    /// Help of `command`, or of all commands and the buttons if None
+    pub fn help(&self, command: Option<&str>) -> String {
+        if let Some(name) = command {
+            return match self.commands.get(name) {
+                Some(command) => command.help_text(),
+                None => format!("command {} not found", name),
+            };
+        }
+
         let available_buttons = itertools::join(
             self.controller_state
                 .button_state
@@ -61,10 +462,118 @@ impl<'a> ControllerCli<'a> {
                 .iter(),
             ", ",
         );
-        println!("Button commands:");
-        println!("{}", available_buttons);
-        println!("");
-        self.regular_help().await;
+        let mut names = self.commands.keys().collect::<Vec<_>>();
+        names.sort_unstable();
+        format!(
+            "Button commands:\n{}\n\nCommands:\n{}\n\
+            Commands can be chained using \"&&\"\n\
+            Type \"help <command>\" for the help of a single command.",
+            available_buttons,
+            itertools::join(
+                names
+                    .into_iter()
+                    .map(|name| self.commands[name].help_text()),
+                "\n"
+            ),
+        )
+    }
+
+    /// Does what a Rhai script asked for
+    pub async fn serve_script_request(
+        &mut self,
+        request: ScriptRequest,
+    ) -> Result<String, CliError> {
+        match request {
+            ScriptRequest::Press(buttons) => button_press(self.controller_state, &buttons).await?,
+            ScriptRequest::Release(buttons) => {
+                button_release(self.controller_state, &buttons).await?
+            }
+            ScriptRequest::Push(buttons, sec) => {
+                button_push(self.controller_state, &buttons, sec).await?
+            }
+            ScriptRequest::Command(cmd, args) => return self.execute(&cmd, args).await,
+        }
+        Ok(String::new())
+    }
+
+    /// Returns the result of the script once it finished
+    async fn serve_script_message(
+        &mut self,
+        message: ScriptMessage,
+    ) -> Option<Result<(), RhaiScriptError>> {
+        match message {
+            ScriptMessage::Request(request, reply) => {
+                // The script is gone if nobody is waiting for the reply, it sends Finished next
+                let _ = reply.send(self.serve_script_request(request).await);
+                None
+            }
+            ScriptMessage::Finished(result) => Some(result),
+        }
+    }
+
+    /// Runs `script` to the end without reading input, for running scripts non-interactively
+    pub async fn run_rhai_script(&mut self, mut script: RhaiScript) -> Result<(), RhaiScriptError> {
+        loop {
+            let message = tokio::select! {
+                message = script.next_message() => message,
+                _ = self.controller_state.send() => continue,
+            };
+            if let Some(result) = self.serve_script_message(message).await {
+                return result;
+            }
+        }
+    }
+
+    /// Starts the Rhai script `arg` in the background, or stops the running one if it is "stop"
+    async fn cmd_rhai(&mut self, arg: Option<&str>) -> Result<String, CliError> {
+        match (arg, &self.rhai_script) {
+            (None, _) => Err(CliError::MissingArgument("file".into())),
+            (Some("stop"), Some(script)) => {
+                script.cancel();
+                Ok(format!("Stopping {}", script.get_name()))
+            }
+            (Some("stop"), None) => Err(CliError::NoScriptRunning),
+            (Some(_), Some(script)) => Err(CliError::ScriptRunning(script.get_name().into())),
+            (Some(file), None) => {
+                let script = RhaiScript::load(file, self.controller_state)
+                    .await
+                    .map_err(|why| CliError::ScriptLoad(why.to_string()))?;
+                self.rhai_script = Some(script);
+                Ok(format!("Started {}", file))
+            }
+        }
+    }
+
+    /// Runs the registered command `cmd`, for example from a library user's own input loop
+    pub async fn execute(&mut self, cmd: &str, args: Vec<String>) -> Result<String, CliError> {
+        let command = self
+            .commands
+            .get(cmd)
+            .ok_or_else(|| CliError::UnknownCommand(cmd.into()))?;
+        if let Some(arg) = command.missing_arg(args.len()) {
+            return Err(CliError::MissingArgument(arg.name.clone()));
+        }
+        let arg = args.first().map(|x| x.as_ref());
+        match &command.handler {
+            CommandHandler::Function(handler) => handler(self.controller_state, args).await,
+            CommandHandler::Help => Ok(self.help(arg)),
+            CommandHandler::Exit => {
+                self.exit_requested = true;
+                Ok(String::new())
+            }
+            CommandHandler::Source => {
+                let file = arg.unwrap();
+                let script = Script::load(file)
+                    .await
+                    .map_err(|why| CliError::ScriptLoad(why.to_string()))?;
+                // Boxed, as scripts execute commands themselves
+                Box::pin(script.run(self))
+                    .await
+                    .map_err(|why| CliError::ScriptFailed(file.into(), why.to_string()))?;
+                Ok(String::new())
+            }
+            CommandHandler::Rhai => self.cmd_rhai(arg).await,
+        }
     }
 
     /// Reimplement if other behavior is needed
@@ -73,25 +582,41 @@ impl<'a> ControllerCli<'a> {
         let mut buttons_to_push = Vec::new();
         'inputloop: loop {
             let user_input = self.read_input_line().await;
+            // Rhai scripts served while reading can exit as well
+            if std::mem::take(&mut self.exit_requested) {
+                break;
+            }
 
             for command in user_input.split("&&") {
-                let mut args = shlex::split(command).unwrap();
+                let mut args = match shlex::split(command) {
+                    Some(args) if !args.is_empty() => args,
+                    Some(_) => continue,
+                    None => {
+                        println!("{}", CliError::InvalidQuoting);
+                        continue;
+                    }
+                };
                 let cmd = args.remove(0);
-                if cmd == "exit" {
-                    break 'inputloop;
-                } else if cmd == "help" {
-                    self.regular_help().await;
-                } else if cmd == "stick" {
-                    Self::cmd_stick(self.controller_state, &args.iter().map(|x| x.as_ref()).collect::<Vec<&str>>()).await;
-                } else if self.controller_state.button_state.get_available_buttons().contains(&cmd.as_ref()) {
-                    buttons_to_push.push(cmd.clone())
+                if self.commands.contains_key(&cmd)
+                    || self.controller_state.button_state.resolve(&cmd).is_err()
+                {
+                    match self.execute(&cmd, args).await {
+                        Ok(result) if result.is_empty() => {}
+                        Ok(result) => println!("{}", result),
+                        Err(why) => println!("{}", why),
+                    }
+                    if std::mem::take(&mut self.exit_requested) {
+                        break 'inputloop;
+                    }
                 } else {
-                    println!("command {} not found, call help for help.", cmd);
+                    buttons_to_push.push(cmd.clone())
                 }
             }
 
             if !buttons_to_push.is_empty() {
-                button_push(self.controller_state, &buttons_to_push, None).await.unwrap();
+                if let Err(why) = button_push(self.controller_state, &buttons_to_push, None).await {
+                    println!("{}", why);
+                }
             } else {
                 self.controller_state.send().await;
             }
@@ -102,44 +627,335 @@ impl<'a> ControllerCli<'a> {
     async fn cmd_stick(
         controller_state: &mut ControllerState,
         args: &[&str],
-    ) -> String {
-        let mut args_iter = args.into_iter();
-        let side = args_iter.next().unwrap();
-        let direction = args_iter.next().unwrap();
-        let value = args_iter.next();
-        if *side == "l" || *side == "left" {
-            let stick = controller_state.l_stick_state.as_mut().unwrap();
-            Self::set_stick(stick, direction.parse().unwrap(), value.copied()).unwrap()
+    ) -> Result<String, CliError> {
+        let side = args
+            .first()
+            .ok_or_else(|| CliError::MissingArgument("side".into()))?;
+        let direction = args
+            .get(1)
+            .ok_or_else(|| CliError::MissingArgument("direction".into()))?;
+        let direction = direction
+            .parse()
+            .map_err(|_| CliError::InvalidArgument("direction", direction.to_string()))?;
+        let values = &args[2..];
+        let stick = if *side == "l" || *side == "left" {
+            controller_state.l_stick_state.as_mut()
         } else if *side == "r" || *side == "right" {
-            let stick = controller_state.r_stick_state.as_mut().unwrap();
-            Self::set_stick(stick, direction.parse().unwrap(), value.copied()).unwrap()
+            controller_state.r_stick_state.as_mut()
         } else {
-            panic!("Unexpected argument {}", direction)
+            return Err(CliError::InvalidArgument("side", side.to_string()));
+        };
+        let stick = stick.ok_or_else(|| CliError::StickNotAvailable(side.to_string()))?;
+        Self::set_stick(stick, direction, values)
+    }
+
+    /// Starts a motion that is advanced with every input report, so it returns right away
+    fn cmd_motion(
+        controller_state: &mut ControllerState,
+        args: &[&str],
+    ) -> Result<String, CliError> {
+        let side = *args
+            .first()
+            .ok_or_else(|| CliError::MissingArgument("side".into()))?;
+        if side == "stop" {
+            controller_state.stop_stick_motions();
+            return Ok("Stopped the stick motions".into());
+        }
+        let left = match side {
+            "l" | "left" => true,
+            "r" | "right" => false,
+            _ => return Err(CliError::InvalidArgument("side", side.into())),
+        };
+        let stick_available = if left {
+            controller_state.l_stick_state.is_some()
+        } else {
+            controller_state.r_stick_state.is_some()
+        };
+        if !stick_available {
+            return Err(CliError::StickNotAvailable(side.into()));
         }
+
+        let required = |i: usize, name: &'static str| -> Result<&str, CliError> {
+            args.get(i)
+                .copied()
+                .ok_or_else(|| CliError::MissingArgument(name.into()))
+        };
+        let number = |i: usize, name: &'static str| -> Result<f32, CliError> {
+            let value = required(i, name)?;
+            value
+                .parse()
+                .map_err(|_| CliError::InvalidArgument(name, value.into()))
+        };
+        let kind = required(1, "kind")?;
+        let seconds = number(2, "seconds")?;
+        let duration = Duration::try_from_secs_f32(seconds)
+            .map_err(|_| CliError::InvalidArgument("seconds", seconds.to_string()))?;
+        let motion = match kind {
+            "ramp" => {
+                let to = (number(3, "value")? / 100.0, number(4, "value2")? / 100.0);
+                let easing = args
+                    .get(5)
+                    .map(|easing| {
+                        easing
+                            .parse()
+                            .map_err(|_| CliError::InvalidArgument("easing", easing.to_string()))
+                    })
+                    .transpose()?;
+                StickMotion::ramp(to, duration, easing)
+            }
+            "circle" => {
+                let magnitude = if args.len() > 4 {
+                    number(4, "value2")?
+                } else {
+                    1.0
+                };
+                // Starting where the stick is pointing, or to the right if it is centered
+                let stick = if left {
+                    &controller_state.l_stick_state
+                } else {
+                    &controller_state.r_stick_state
+                };
+                let start_angle = match stick.as_ref().unwrap().get_polar()? {
+                    (angle, magnitude) if magnitude > 0.0 => angle.to_degrees(),
+                    _ => 0.0,
+                };
+                StickMotion::circle(start_angle, number(3, "value")?, magnitude, duration)
+            }
+            "flick" => StickMotion::flick(number(3, "value")?, duration),
+            _ => return Err(CliError::InvalidArgument("kind", kind.into())),
+        };
+        if left {
+            controller_state.set_l_stick_motion(motion)
+        } else {
+            controller_state.set_r_stick_motion(motion)
+        }
+        Ok(format!(
+            "Started {} motion on the {} stick",
+            kind,
+            if left { "left" } else { "right" }
+        ))
     }
 
-    /// `value` is only used for StickDirection::{Horizontal, Vertical}, so you can set it to any
-    /// value or just default to 0
+    async fn cmd_nfc(
+        controller_state: &mut ControllerState,
+        amiibo_library: Option<&mut AmiiboLibrary>,
+        args: &[&str],
+    ) -> Result<String, CliError> {
+        let action = args.first().copied().unwrap_or("list");
+        if action == "remove" {
+            return Ok(if controller_state.remove_nfc().is_some() {
+                "Removed NFC tag".into()
+            } else {
+                "No NFC tag to remove".into()
+            });
+        }
+        if action == "restore" {
+            let generation = match args.get(1) {
+                Some(generation) => generation
+                    .parse()
+                    .map_err(|_| CliError::InvalidArgument("generation", generation.to_string()))?,
+                None => 0,
+            };
+            let tag = controller_state.get_nfc_mut().ok_or(CliError::NoNfcTag)?;
+            tag.restore_backup(generation)
+                .map_err(|why| CliError::NfcRestore(why.to_string()))?;
+            return Ok(format!("Restored backup generation {}", generation));
+        }
+
+        let library = amiibo_library.ok_or(CliError::NoAmiiboLibrary)?;
+        let loaded = match action {
+            "list" => {
+                let current = library.current().map(|entry| entry.path.clone());
+                return Ok(itertools::join(
+                    library.entries().iter().enumerate().map(|(i, entry)| {
+                        let marker = if Some(&entry.path) == current.as_ref() {
+                            "*"
+                        } else {
+                            " "
+                        };
+                        format!("{}{:3} {}", marker, i, entry)
+                    }),
+                    "\n",
+                ));
+            }
+            "load" => {
+                library
+                    .load(
+                        args.get(1)
+                            .ok_or_else(|| CliError::MissingArgument("name".into()))?,
+                    )
+                    .await
+            }
+            "cycle" => library.cycle().await,
+            _ => return Err(CliError::InvalidArgument("action", action.into())),
+        };
+        let tag = loaded.map_err(|why| CliError::AmiiboLoad(why.to_string()))?;
+        controller_state.set_nfc(tag);
+        Ok(format!("Loaded {}", library.current().unwrap()))
+    }
+
+    async fn cmd_ir(
+        controller_state: &mut ControllerState,
+        args: &[&str],
+    ) -> Result<String, CliError> {
+        if args.is_empty() {
+            return Err(CliError::MissingArgument("images".into()));
+        } else if args == ["pattern"] {
+            controller_state.set_ir_source(IrFrameSource::Pattern);
+            return Ok("IR camera shows the test pattern".into());
+        }
+        let mut images = Vec::with_capacity(args.len());
+        for file in args {
+            let image = IrImage::load(file)
+                .await
+                .map_err(|why| CliError::IrImageLoad(file.to_string(), why.to_string()))?;
+            images.push(image);
+        }
+        controller_state.set_ir_source(IrFrameSource::Images(images));
+        Ok(format!("IR camera shows {} images", args.len()))
+    }
+
+    fn cmd_button_mode(
+        controller_state: &mut ControllerState,
+        mode: &str,
+        args: &[&str],
+    ) -> Result<String, CliError> {
+        let button_state = &mut controller_state.button_state;
+        let button = button_state.resolve(
+            args.first()
+                .ok_or_else(|| CliError::MissingArgument("button".into()))?,
+        )?;
+        if mode == "toggle" {
+            let pressed = button_state.toggle(button)?;
+            return Ok(format!(
+                "{} is {}",
+                button,
+                if pressed { "pressed" } else { "released" }
+            ));
+        }
+
+        let value = *args
+            .get(1)
+            .ok_or_else(|| CliError::MissingArgument("value".into()))?;
+        let invalid_value = || CliError::InvalidArgument("value", value.into());
+        if mode == "turbo" && value == "off" {
+            button_state.stop_mode(button)?;
+            Ok(format!("Stopped turbo on {}", button))
+        } else if mode == "turbo" {
+            let frequency = value.parse().map_err(|_| invalid_value())?;
+            button_state.set_turbo(button, frequency)?;
+            Ok(format!("Turbo on {} at {}Hz", button, frequency))
+        } else if let Some(frames) = value.strip_suffix('f') {
+            let frames = frames.parse().map_err(|_| invalid_value())?;
+            button_state.set_hold_frames(button, frames)?;
+            Ok(format!("Holding {} for {} reports", button, frames))
+        } else {
+            let seconds = value
+                .parse()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f32(seconds).ok())
+                .ok_or_else(invalid_value)?;
+            button_state.set_hold(button, seconds)?;
+            Ok(format!("Holding {} for {}s", button, seconds.as_secs_f32()))
+        }
+    }
+
+    fn cmd_history(
+        controller_state: &mut ControllerState,
+        action: Option<&str>,
+    ) -> Result<String, CliError> {
+        let history = controller_state.get_history_mut();
+        match action {
+            Some("clear") => {
+                history.clear();
+                Ok("Cleared the input history".into())
+            }
+            Some("stats") => Ok(itertools::join(
+                history
+                    .all_stats()
+                    .into_iter()
+                    .map(|(button, stats)| format!("{}: {}", button, stats)),
+                "\n",
+            )),
+            Some(count) => match count.parse() {
+                Ok(count) => Ok(itertools::join(history.last(count), "\n")),
+                Err(_) => Err(CliError::InvalidArgument("action", count.into())),
+            },
+            None => Ok(itertools::join(history.last(20), "\n")),
+        }
+    }
+
+    fn cmd_profile(
+        controller_state: &mut ControllerState,
+        button_profiles: Option<&ButtonProfiles>,
+        name: Option<&str>,
+    ) -> Result<String, CliError> {
+        if name == Some("off") {
+            controller_state.button_state.set_profile(None);
+            return Ok("Removed button profile".into());
+        }
+        let profiles = button_profiles.ok_or(CliError::NoButtonProfiles)?;
+        match name {
+            None => Ok(itertools::join(profiles.names(), "\n")),
+            Some(name) => {
+                controller_state
+                    .button_state
+                    .set_profile(Some(profiles.get(name)?.clone()));
+                Ok(format!("Using button profile {}", name))
+            }
+        }
+    }
+
+    /// `values` are only used for StickDirection::{Horizontal, Vertical, Angle, Percent}, so you
+    /// can leave them empty for the other directions
     fn set_stick(
         stick: &mut StickState,
         direction: StickDirection,
-        value: Option<&str>,
-    ) -> Result<String, InvalidStickValue> {
+        values: &[&str],
+    ) -> Result<String, CliError> {
+        let value = |i: usize, name: &'static str| -> Result<Option<f32>, CliError> {
+            values
+                .get(i)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| CliError::InvalidArgument(name, value.to_string()))
+                })
+                .transpose()
+        };
+        let required = |i: usize, name: &'static str| -> Result<f32, CliError> {
+            value(i, name)?.ok_or_else(|| CliError::MissingArgument(name.into()))
+        };
         match direction {
-            // Not sure if just unwrapping these is a good idea... But I don't want to make yet
-            // another error type that just combines those
-            StickDirection::Center => stick.set_center().unwrap(),
-            StickDirection::Up => stick.set_up().unwrap(),
-            StickDirection::Down => stick.set_down().unwrap(),
-            StickDirection::Left => stick.set_left().unwrap(),
-            StickDirection::Right => stick.set_right().unwrap(),
-            StickDirection::Horizontal => {
-                let value = value.expect("Missing value").parse().unwrap();
-                stick.set_h(value)?
-            }
-            StickDirection::Vertical => {
-                let value = value.expect("Missing value").parse().unwrap();
-                stick.set_v(value)?
+            StickDirection::Center => stick.set_center()?,
+            StickDirection::Up => stick.set_up()?,
+            StickDirection::Down => stick.set_down()?,
+            StickDirection::Left => stick.set_left()?,
+            StickDirection::Right => stick.set_right()?,
+            StickDirection::UpLeft => stick.set_up_left()?,
+            StickDirection::UpRight => stick.set_up_right()?,
+            StickDirection::DownLeft => stick.set_down_left()?,
+            StickDirection::DownRight => stick.set_down_right()?,
+            StickDirection::Angle => {
+                let angle = required(0, "value")?;
+                let magnitude = value(1, "value2")?.unwrap_or(1.0);
+                stick.set_angle(angle, magnitude)?
+            }
+            StickDirection::Percent => {
+                stick.set_percent(required(0, "value")?, required(1, "value2")?)?
+            }
+            StickDirection::Horizontal | StickDirection::Vertical => {
+                let raw = values
+                    .first()
+                    .ok_or_else(|| CliError::MissingArgument("value".into()))?;
+                let raw = raw
+                    .parse()
+                    .map_err(|_| CliError::InvalidArgument("value", raw.to_string()))?;
+                if direction == StickDirection::Horizontal {
+                    stick.set_h(raw)?
+                } else {
+                    stick.set_v(raw)?
+                }
             }
         }
         Ok(format!(
@@ -149,3 +965,56 @@ impl<'a> ControllerCli<'a> {
         ))
     }
 }
+
+/// Errors of CLI commands, reported to the user without ending the session
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("command {0} not found, call help for help.")]
+    UnknownCommand(String),
+    #[error("Missing argument {0}.")]
+    MissingArgument(String),
+    #[error("Invalid {0} \"{1}\".")]
+    InvalidArgument(&'static str, String),
+    #[error("Unbalanced quotes in command.")]
+    InvalidQuoting,
+    #[error("The controller has no {0} stick.")]
+    StickNotAvailable(String),
+    #[error("No amiibo library loaded.")]
+    NoAmiiboLibrary,
+    #[error("Couldn't load amiibo: {0}")]
+    AmiiboLoad(String),
+    #[error("No NFC tag loaded.")]
+    NoNfcTag,
+    #[error("Couldn't restore the NFC tag: {0}")]
+    NfcRestore(String),
+    #[error("Couldn't load IR image {0}: {1}")]
+    IrImageLoad(String, String),
+    #[error("No button profiles loaded.")]
+    NoButtonProfiles,
+    #[error("Couldn't load script: {0}")]
+    ScriptLoad(String),
+    #[error("{0}: {1}")]
+    ScriptFailed(String, String),
+    #[error("Script {0} is still running, stop it with \"rhai stop\".")]
+    ScriptRunning(String),
+    #[error("No script is running.")]
+    NoScriptRunning,
+    #[error(transparent)]
+    ProfileNotFound(#[from] ProfileNotFound),
+    #[error(transparent)]
+    InvalidStickValue(#[from] InvalidStickValue),
+    #[error(transparent)]
+    NoCalibrationDataAvailable(#[from] NoCalibrationDataAvailable),
+    #[error(transparent)]
+    ButtonState(#[from] ButtonStateError),
+    #[error(transparent)]
+    Combo(#[from] ComboError),
+    /// For commands registered by library users
+    #[error("{0}")]
+    Other(String),
+}
+
+#[inline]
+fn as_strs(args: &[String]) -> Vec<&str> {
+    args.iter().map(|x| x.as_ref()).collect()
+}
@@ -1,22 +1,99 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use rsevents::{EventState, ManualResetEvent};
+use log::warn;
+use tokio::{
+    sync::{broadcast, Notify},
+    time::Instant,
+};
 
 use crate::{
-    button_state::ButtonState, controller::Controller, memory::FlashMemory, nfc_tag::NFCTag,
-    stick_calibration::StickCalibration, stick_state::StickState,
+    button_state::ButtonState, controller::Controller, input_history::InputHistory,
+    ir_camera::IrFrameSource, memory::FlashMemory, nfc_tag::NFCTag,
+    stick_calibration::StickCalibration, stick_motion::StickMotion, stick_state::StickState,
+    transport::ReportTransport,
 };
 
+/// Standard input reports are sent at 60Hz
+pub const STANDARD_REPORT_PERIOD: Duration = Duration::from_micros(16_667);
+/// HID header, report ID, timer, battery, buttons, sticks, vibrator and 3 zeroed IMU samples
+pub const STANDARD_REPORT_SIZE: usize = 50;
+const STANDARD_REPORT_ID: u8 = 0x30;
+/// Full battery, powered by the Switch
+const BATTERY_CONNECTION_INFO: u8 = 0x8E;
+const VIBRATOR_INPUT: u8 = 0x80;
+
+/// Events that are kept for slow subscribers before they miss some
+const EVENT_CHANNEL_LEN: usize = 64;
+
+/// Something the Switch told the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControllerEvent {
+    /// Raw rumble data of an output report, 4 bytes for each side
+    Rumble([u8; 8]),
+    /// Argument of the set player lights subcommand, the low nibble is lit and the high one
+    /// flashing
+    PlayerLights(u8),
+}
+
+/// Number of input reports sent so far, for tasks that wait for reports without sending them
+#[derive(Debug, Default)]
+pub struct ReportCounter {
+    count: AtomicU64,
+    sent: Notify,
+}
+
+impl ReportCounter {
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    fn increment(&self) {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        self.sent.notify_waiters();
+    }
+
+    /// Returns once `count` reports were sent in total
+    pub async fn wait_until(&self, count: u64) {
+        loop {
+            // Registered before checking, so a report sent in between isn't missed
+            let sent = self.sent.notified();
+            if self.get() >= count {
+                return;
+            }
+            sent.await;
+        }
+    }
+}
+
 // Protocol is ignored for now because that causes cyclic referencing or self-referencing, which is
 // hard and there is probably a better way to do what the original code does
 pub struct ControllerState {
     controller: Controller,
     nfc_content: Option<NFCTag>,
+    nfc_remove_requested: bool,
+    /// Frame source for the IR camera, until the MCU takes it
+    ir_source: Option<IrFrameSource>,
     spi_flash: Option<FlashMemory>,
     pub button_state: ButtonState,
     pub l_stick_state: Option<StickState>,
     pub r_stick_state: Option<StickState>,
-    pub sig_is_send: Arc<ManualResetEvent>,
+    l_stick_motion: Option<StickMotion>,
+    r_stick_motion: Option<StickMotion>,
+    history: InputHistory,
+    /// None while the controller isn't connected
+    transport: Option<Box<dyn ReportTransport>>,
+    report_period: Duration,
+    last_report: Option<Instant>,
+    timer: u8,
+    reports: Arc<ReportCounter>,
+    events: broadcast::Sender<ControllerEvent>,
 }
 
 impl ControllerState {
@@ -62,11 +139,21 @@ impl ControllerState {
         Self {
             controller,
             nfc_content: None,
+            nfc_remove_requested: false,
+            ir_source: None,
             spi_flash,
             button_state,
             l_stick_state,
             r_stick_state,
-            sig_is_send: Arc::new(ManualResetEvent::new(EventState::Unset)),
+            l_stick_motion: None,
+            r_stick_motion: None,
+            history: InputHistory::default(),
+            transport: None,
+            report_period: STANDARD_REPORT_PERIOD,
+            last_report: None,
+            timer: 0,
+            reports: Arc::new(ReportCounter::default()),
+            events: broadcast::channel(EVENT_CHANNEL_LEN).0,
         }
     }
 
@@ -80,9 +167,37 @@ impl ControllerState {
         self.spi_flash.as_ref()
     }
 
-    #[inline]
+    /// Replacing a tag makes the MCU report the old one as removed before the new one appears
     pub fn set_nfc(&mut self, data: NFCTag) {
-        self.nfc_content = Some(data)
+        if self.nfc_content.replace(data).is_some() {
+            self.nfc_remove_requested = true
+        }
+    }
+
+    pub fn remove_nfc(&mut self) -> Option<NFCTag> {
+        let removed = self.nfc_content.take();
+        if removed.is_some() {
+            self.nfc_remove_requested = true
+        }
+        removed
+    }
+
+    /// Returns whether the tag was removed or replaced since the last call
+    #[inline]
+    pub fn take_nfc_remove_request(&mut self) -> bool {
+        std::mem::take(&mut self.nfc_remove_requested)
+    }
+
+    /// The MCU switches to the new source with the next frame it sends
+    #[inline]
+    pub fn set_ir_source(&mut self, source: IrFrameSource) {
+        self.ir_source = Some(source)
+    }
+
+    /// Returns the source set since the last call, if any
+    #[inline]
+    pub fn take_ir_source(&mut self) -> Option<IrFrameSource> {
+        self.ir_source.take()
     }
 
     #[inline]
@@ -95,11 +210,238 @@ impl ControllerState {
         self.nfc_content.as_mut()
     }
 
-    pub async fn send(&self) {
-        todo!()
+    /// Replaces the running motion of the left stick, if any
+    #[inline]
+    pub fn set_l_stick_motion(&mut self, motion: StickMotion) {
+        self.l_stick_motion = Some(motion)
+    }
+
+    #[inline]
+    pub fn set_r_stick_motion(&mut self, motion: StickMotion) {
+        self.r_stick_motion = Some(motion)
+    }
+
+    /// Stops the motions where they are, the sticks keep their current position
+    pub fn stop_stick_motions(&mut self) {
+        self.l_stick_motion = None;
+        self.r_stick_motion = None;
+    }
+
+    #[inline]
+    pub fn stick_motions_finished(&self) -> bool {
+        self.l_stick_motion.is_none() && self.r_stick_motion.is_none()
+    }
+
+    /// Called before every input report with the time since the previous one, advances timed
+    /// buttons and stick motions and records the resulting state in the history
+    fn advance(&mut self, elapsed: Duration) {
+        self.button_state.advance(elapsed);
+        self.advance_stick_motions(elapsed);
+    }
+
+    #[inline]
+    pub fn get_history(&self) -> &InputHistory {
+        &self.history
+    }
+
+    #[inline]
+    pub fn get_history_mut(&mut self) -> &mut InputHistory {
+        &mut self.history
+    }
+
+    /// Finished motions are removed
+    fn advance_stick_motions(&mut self, elapsed: Duration) {
+        for (motion, stick) in [
+            (&mut self.l_stick_motion, &mut self.l_stick_state),
+            (&mut self.r_stick_motion, &mut self.r_stick_state),
+        ] {
+            let (Some(running), Some(stick)) = (motion.as_mut(), stick.as_mut()) else {
+                *motion = None;
+                continue;
+            };
+            if let Err(why) = running.advance(stick, elapsed) {
+                warn!("Stopping stick motion: {}", why);
+                *motion = None;
+            } else if running.is_finished() {
+                *motion = None;
+            }
+        }
+    }
+
+    /// Sets where the input reports go. Without a transport the reports are produced and
+    /// dropped, so waiting for reports works the same while the controller isn't connected.
+    #[inline]
+    pub fn set_transport(&mut self, transport: Option<Box<dyn ReportTransport>>) {
+        self.transport = transport
+    }
+
+    #[inline]
+    pub fn set_report_period(&mut self, report_period: Duration) {
+        self.report_period = report_period
+    }
+
+    /// For other tasks to wait for reports sent by the owner of the state
+    #[inline]
+    pub fn get_report_counter(&self) -> Arc<ReportCounter> {
+        self.reports.clone()
+    }
+
+    /// Waits until the next input report is due and sends it with the current state. Cancel safe,
+    /// nothing is sent if the future is dropped before.
+    pub async fn send(&mut self) {
+        if let Some(last_report) = self.last_report {
+            tokio::time::sleep_until(last_report + self.report_period).await;
+        }
+        self.send_report();
+    }
+
+    /// Sends `reports` more input reports at the report rate
+    pub async fn wait_for_reports(&mut self, reports: u32) {
+        let target = self.reports.get() + reports as u64;
+        while self.reports.get() < target {
+            self.send().await;
+        }
+    }
+
+    /// Like `tokio::time::sleep`, but keeps sending input reports in the meantime
+    pub async fn sleep(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        loop {
+            let next_report = self
+                .last_report
+                .map_or_else(Instant::now, |last_report| last_report + self.report_period);
+            if next_report > deadline {
+                tokio::time::sleep_until(deadline).await;
+                return;
+            }
+            self.send().await;
+        }
+    }
+
+    fn send_report(&mut self) {
+        let now = Instant::now();
+        let elapsed = self
+            .last_report
+            .map_or(self.report_period, |last_report| now - last_report);
+        self.last_report = Some(now);
+        self.advance(elapsed);
+
+        let report = self.standard_report(elapsed);
+        // The sticks as reported, with their noise
+        let sent_stick = |present: bool, bytes: &[u8]| {
+            present.then(|| {
+                let stick = StickState::from(&[bytes[0], bytes[1], bytes[2]]);
+                (stick.get_h(), stick.get_v())
+            })
+        };
+        self.history.record(
+            elapsed,
+            &self.button_state,
+            sent_stick(self.l_stick_state.is_some(), &report[7..10]),
+            sent_stick(self.r_stick_state.is_some(), &report[10..13]),
+        );
+        if let Some(transport) = &mut self.transport {
+            if let Err(why) = transport.write(&report) {
+                warn!("Couldn't send input report: {}", why);
+            }
+        }
+        self.reports.increment();
+    }
+
+    /// Standard full input report (0x30) of the current state, without IMU data
+    fn standard_report(&mut self, elapsed: Duration) -> [u8; STANDARD_REPORT_SIZE] {
+        let mut report = [0; STANDARD_REPORT_SIZE];
+        report[0] = 0xA1;
+        report[1] = STANDARD_REPORT_ID;
+        report[2] = self.timer;
+        self.timer = self.timer.wrapping_add(1);
+        report[3] = BATTERY_CONNECTION_INFO;
+        report[4..7].copy_from_slice(&self.button_state.as_bytes());
+        if let Some(stick) = &mut self.l_stick_state {
+            report[7..10].copy_from_slice(&stick.as_noisy_bytes(elapsed));
+        }
+        if let Some(stick) = &mut self.r_stick_state {
+            report[10..13].copy_from_slice(&stick.as_noisy_bytes(elapsed));
+        }
+        report[13] = VIBRATOR_INPUT;
+        report
+    }
+
+    /// For the task reading output reports, which runs next to the one sending input reports, see
+    /// `OutputReportHandler`
+    #[inline]
+    pub fn get_event_sender(&self) -> broadcast::Sender<ControllerEvent> {
+        self.events.clone()
+    }
+
+    #[inline]
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ControllerEvent> {
+        self.events.subscribe()
     }
 
     pub async fn connect(&self) {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        button_state::button_push_frames,
+        input_history::InputChange,
+        stick_noise::{StickNoise, StickNoiseConfig},
+        transport::connected_state,
+    };
+
+    #[tokio::test]
+    async fn held_frames_are_in_exactly_that_many_reports() {
+        let (mut controller_state, transport) = connected_state();
+        controller_state.wait_for_reports(2).await;
+        button_push_frames(&mut controller_state, &["a".into()], 3)
+            .await
+            .unwrap();
+        controller_state.wait_for_reports(2).await;
+
+        let reports = transport.take_reports();
+        let pressed = reports
+            .iter()
+            .map(|report| report[4] & 0x10 != 0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pressed,
+            [false, false, true, true, true, false, false, false]
+        );
+        let timers = reports.iter().map(|report| report[2]).collect::<Vec<_>>();
+        assert_eq!(timers, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(controller_state.get_report_counter().get(), 8);
+    }
+
+    #[tokio::test]
+    async fn other_tasks_see_every_report() {
+        let (mut controller_state, _transport) = connected_state();
+        let reports = controller_state.get_report_counter();
+        let waiter = tokio::spawn(async move { reports.wait_until(5).await });
+        controller_state.wait_for_reports(5).await;
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn history_has_the_noisy_stick_values() {
+        let (mut controller_state, transport) = connected_state();
+        let noise = StickNoiseConfig::from_yaml("quantization: 1000").unwrap();
+        let stick = controller_state.l_stick_state.as_mut().unwrap();
+        stick.set_noise(Some(StickNoise::new(noise)));
+        stick.set_h(2500).unwrap();
+        controller_state.wait_for_reports(1).await;
+
+        let reports = transport.take_reports();
+        let sent = StickState::from(&[reports[0][7], reports[0][8], reports[0][9]]);
+        assert_eq!(sent.get_h(), 2000);
+        let event = controller_state.get_history().events().next().unwrap();
+        assert_eq!(event.change, InputChange::LeftStick(2000, sent.get_v()));
+    }
+}
@@ -0,0 +1,272 @@
+//! Firmware update mode of the MCU.
+//!
+//! The update protocol of the real MCU is not documented. The operations of the 0x06 request, their
+//! argument layouts and the 0x06 reply implemented here are placeholders of this emulator, not what
+//! a console sends. They only exist so that the update power state can be exercised.
+
+use std::fmt::Display;
+
+use log::{info, warn};
+
+use crate::mcu::mcu_crc;
+
+/// MCU firmware version as reported in the status message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Default for FirmwareVersion {
+    fn default() -> Self {
+        Self {
+            major: 0x0008,
+            minor: 0x001B,
+        }
+    }
+}
+
+impl FirmwareVersion {
+    #[inline]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            major: u16::from_be_bytes([bytes[0], bytes[1]]),
+            minor: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> [u8; 4] {
+        let [major_0, major_1] = self.major.to_be_bytes();
+        let [minor_0, minor_1] = self.minor.to_be_bytes();
+        [major_0, major_1, minor_0, minor_1]
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Largest accepted image. Update requests carry the size, so it is checked before reserving
+/// memory for the image.
+pub const MAX_FIRMWARE_SIZE: usize = 0x40000;
+
+/// First argument byte of an update request, a placeholder layout (see the module docs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum UpdateOperation {
+    /// Image size (u32 LE), new version (major u16 BE, minor u16 BE)
+    Begin = 0x00,
+    /// Offset (u32 LE), length (u8), data, checksum of everything from the offset on
+    Chunk = 0x01,
+    /// Checksum of the whole image
+    Finish = 0x02,
+}
+
+impl TryFrom<u8> for UpdateOperation {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Begin),
+            0x01 => Ok(Self::Chunk),
+            0x02 => Ok(Self::Finish),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Result byte of an update reply, a placeholder layout (see the module docs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum UpdateResult {
+    Ok = 0x00,
+    ChecksumMismatch = 0x01,
+    UnexpectedOffset = 0x02,
+    Incomplete = 0x03,
+    NotStarted = 0x04,
+    /// The request is shorter than its layout or the image is larger than `MAX_FIRMWARE_SIZE`
+    Malformed = 0x05,
+}
+
+/// Firmware image being received by the MCU
+#[derive(Debug, Clone)]
+pub struct FirmwareUpdate {
+    size: usize,
+    version: FirmwareVersion,
+    image: Vec<u8>,
+}
+
+impl FirmwareUpdate {
+    pub fn begin(args: &[u8]) -> Result<Self, UpdateResult> {
+        let Some(args) = args.get(0..8) else {
+            warn!("MCU: truncated firmware update start");
+            return Err(UpdateResult::Malformed);
+        };
+        let size = u32::from_le_bytes(args[0..4].try_into().unwrap()) as usize;
+        if size > MAX_FIRMWARE_SIZE {
+            warn!("MCU: firmware image of {} bytes is too large", size);
+            return Err(UpdateResult::Malformed);
+        }
+        let version = FirmwareVersion::from_bytes(&args[4..8]);
+        info!(
+            "MCU: firmware update to {} started, {} bytes",
+            version, size
+        );
+        Ok(Self {
+            size,
+            version,
+            image: Vec::with_capacity(size),
+        })
+    }
+
+    /// Bytes received so far
+    #[inline]
+    pub fn progress(&self) -> usize {
+        self.image.len()
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> FirmwareVersion {
+        self.version
+    }
+
+    /// Chunks have to arrive in order, a rejected chunk has to be sent again
+    pub fn write_chunk(&mut self, args: &[u8]) -> UpdateResult {
+        let Some(&len) = args.get(4) else {
+            warn!("MCU: truncated firmware chunk header");
+            return UpdateResult::Malformed;
+        };
+        let len = len as usize;
+        let (Some(data), Some(&checksum)) = (args.get(5..(5 + len)), args.get(5 + len)) else {
+            warn!("MCU: truncated firmware chunk of {} bytes", len);
+            return UpdateResult::Malformed;
+        };
+        let offset = u32::from_le_bytes(args[0..4].try_into().unwrap()) as usize;
+        if mcu_crc(&args[..(5 + len)]) != checksum {
+            warn!("MCU: firmware chunk at {:#x} has a bad checksum", offset);
+            return UpdateResult::ChecksumMismatch;
+        }
+        if offset != self.image.len() || offset + len > self.size {
+            warn!(
+                "MCU: firmware chunk at {:#x}, expected {:#x}",
+                offset,
+                self.image.len()
+            );
+            return UpdateResult::UnexpectedOffset;
+        }
+        self.image.extend_from_slice(data);
+        UpdateResult::Ok
+    }
+
+    pub fn finish(&self, args: &[u8]) -> UpdateResult {
+        let Some(&checksum) = args.first() else {
+            return UpdateResult::Malformed;
+        };
+        if self.image.len() != self.size {
+            UpdateResult::Incomplete
+        } else if mcu_crc(&self.image) != checksum {
+            UpdateResult::ChecksumMismatch
+        } else {
+            info!("MCU: firmware update to {} finished", self.version);
+            UpdateResult::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn begin_args(size: u32) -> Vec<u8> {
+        [size.to_le_bytes().as_slice(), &[0x00, 0x08, 0x00, 0x1C]].concat()
+    }
+
+    fn chunk_args(offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut args = [offset.to_le_bytes().as_slice(), &[data.len() as u8], data].concat();
+        args.push(mcu_crc(&args));
+        args
+    }
+
+    #[test]
+    fn image_is_received_in_order() {
+        let mut update = FirmwareUpdate::begin(&begin_args(6)).unwrap();
+        assert_eq!(
+            update.get_version(),
+            FirmwareVersion {
+                major: 8,
+                minor: 0x1C
+            }
+        );
+        assert_eq!(
+            update.write_chunk(&chunk_args(4, &[5, 6])),
+            UpdateResult::UnexpectedOffset
+        );
+        assert_eq!(
+            update.write_chunk(&chunk_args(0, &[1, 2, 3, 4])),
+            UpdateResult::Ok
+        );
+        assert_eq!(update.finish(&[0]), UpdateResult::Incomplete);
+        assert_eq!(
+            update.write_chunk(&chunk_args(4, &[5, 6])),
+            UpdateResult::Ok
+        );
+        assert_eq!(update.progress(), 6);
+        assert_eq!(
+            update.finish(&[mcu_crc(&[1, 2, 3, 4, 5, 6])]),
+            UpdateResult::Ok
+        );
+    }
+
+    #[test]
+    fn truncated_requests_are_rejected() {
+        assert_eq!(
+            FirmwareUpdate::begin(&begin_args(6)[..7]).unwrap_err(),
+            UpdateResult::Malformed
+        );
+
+        let mut update = FirmwareUpdate::begin(&begin_args(6)).unwrap();
+        let chunk = chunk_args(0, &[1, 2, 3]);
+        // Without the length, part of the data and the checksum
+        for len in [4, 6, chunk.len() - 1] {
+            assert_eq!(update.write_chunk(&chunk[..len]), UpdateResult::Malformed);
+        }
+        assert_eq!(update.finish(&[]), UpdateResult::Malformed);
+        assert_eq!(update.progress(), 0);
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        assert!(FirmwareUpdate::begin(&begin_args(MAX_FIRMWARE_SIZE as u32)).is_ok());
+        assert_eq!(
+            FirmwareUpdate::begin(&begin_args(MAX_FIRMWARE_SIZE as u32 + 1)).unwrap_err(),
+            UpdateResult::Malformed
+        );
+        assert_eq!(
+            FirmwareUpdate::begin(&begin_args(u32::MAX)).unwrap_err(),
+            UpdateResult::Malformed
+        );
+    }
+
+    #[test]
+    fn checksum_mismatches_are_rejected() {
+        let mut update = FirmwareUpdate::begin(&begin_args(3)).unwrap();
+        let mut chunk = chunk_args(0, &[1, 2, 3]);
+        *chunk.last_mut().unwrap() ^= 0xFF;
+        assert_eq!(update.write_chunk(&chunk), UpdateResult::ChecksumMismatch);
+        assert_eq!(update.progress(), 0);
+
+        assert_eq!(
+            update.write_chunk(&chunk_args(0, &[1, 2, 3])),
+            UpdateResult::Ok
+        );
+        let checksum = mcu_crc(&[1, 2, 3]);
+        assert_eq!(
+            update.finish(&[checksum ^ 0xFF]),
+            UpdateResult::ChecksumMismatch
+        );
+        assert_eq!(update.finish(&[checksum]), UpdateResult::Ok);
+    }
+}
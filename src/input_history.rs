@@ -0,0 +1,322 @@
+use std::{collections::VecDeque, fmt::Display, time::Duration};
+
+use hashbrown::HashMap;
+
+use crate::button_state::{Button, ButtonState};
+
+/// Number of events kept by default
+pub const DEFAULT_HISTORY_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputChange {
+    Pressed(Button),
+    Released(Button),
+    /// Raw (h, v) of the left stick
+    LeftStick(u32, u32),
+    RightStick(u32, u32),
+}
+
+/// A change that first appeared in input report `frame`, sent `time` after recording started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub time: Duration,
+    pub change: InputChange,
+}
+
+impl Display for InputEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "frame {} ({:.3}s): ",
+            self.frame,
+            self.time.as_secs_f32()
+        )?;
+        match self.change {
+            InputChange::Pressed(button) => write!(f, "{} pressed", button),
+            InputChange::Released(button) => write!(f, "{} released", button),
+            InputChange::LeftStick(h, v) => write!(f, "left stick at ({}, {})", h, v),
+            InputChange::RightStick(h, v) => write!(f, "right stick at ({}, {})", h, v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ButtonStats {
+    pub presses: u32,
+    /// Reports the button was pressed in, over all presses
+    pub held_frames: u64,
+    pub held_time: Duration,
+    pub longest_hold_frames: u64,
+    /// Frame and time of the current press
+    pressed_since: Option<(u64, Duration)>,
+}
+
+impl Display for ButtonStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} presses, held for {} frames ({:.3}s), longest {} frames",
+            self.presses,
+            self.held_frames,
+            self.held_time.as_secs_f32(),
+            self.longest_hold_frames
+        )
+    }
+}
+
+/// Ring buffer of what changed between the sent input reports, and press counters. Statistics
+/// are kept for the whole recording, also for events that fell out of the buffer.
+#[derive(Debug, Clone)]
+pub struct InputHistory {
+    events: VecDeque<InputEvent>,
+    capacity: usize,
+    frame: u64,
+    time: Duration,
+    stats: HashMap<Button, ButtonStats>,
+    previous_pressed: Vec<Button>,
+    previous_l_stick: Option<(u32, u32)>,
+    previous_r_stick: Option<(u32, u32)>,
+}
+
+impl Default for InputHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LEN)
+    }
+}
+
+impl InputHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            frame: 0,
+            time: Duration::ZERO,
+            stats: HashMap::new(),
+            previous_pressed: vec![],
+            previous_l_stick: None,
+            previous_r_stick: None,
+        }
+    }
+
+    /// Drops the oldest events if there are more than `capacity`
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.events.len() > capacity {
+            self.events.pop_front();
+        }
+    }
+
+    /// Called by `ControllerState` with the state of every sent input report, `elapsed` being the
+    /// time since the previous one. Sticks are the raw (h, v) in the report, noise included.
+    pub fn record(
+        &mut self,
+        elapsed: Duration,
+        button_state: &ButtonState,
+        l_stick: Option<(u32, u32)>,
+        r_stick: Option<(u32, u32)>,
+    ) {
+        self.frame += 1;
+        self.time += elapsed;
+
+        let flags = button_state.get_flags();
+        let pressed = button_state
+            .get_available_buttons()
+            .iter()
+            .copied()
+            .filter(|button| {
+                button
+                    .flag(button_state.controller)
+                    .is_some_and(|flag| flags.contains(flag))
+            })
+            .collect::<Vec<_>>();
+        for &button in &pressed {
+            if !self.previous_pressed.contains(&button) {
+                let stats = self.stats.entry(button).or_default();
+                stats.presses += 1;
+                stats.pressed_since = Some((self.frame, self.time));
+                self.push(InputChange::Pressed(button));
+            }
+        }
+        for button in std::mem::replace(&mut self.previous_pressed, pressed) {
+            if !self.previous_pressed.contains(&button) {
+                self.end_press(button);
+                self.push(InputChange::Released(button));
+            }
+        }
+
+        if l_stick != self.previous_l_stick {
+            if let Some((h, v)) = l_stick {
+                self.push(InputChange::LeftStick(h, v));
+            }
+            self.previous_l_stick = l_stick;
+        }
+        if r_stick != self.previous_r_stick {
+            if let Some((h, v)) = r_stick {
+                self.push(InputChange::RightStick(h, v));
+            }
+            self.previous_r_stick = r_stick;
+        }
+    }
+
+    fn end_press(&mut self, button: Button) {
+        let (frame, time) = (self.frame, self.time);
+        let stats = self.stats.entry(button).or_default();
+        if let Some((since_frame, since_time)) = stats.pressed_since.take() {
+            let frames = frame - since_frame;
+            stats.held_frames += frames;
+            stats.held_time += time - since_time;
+            stats.longest_hold_frames = stats.longest_hold_frames.max(frames);
+        }
+    }
+
+    fn push(&mut self, change: InputChange) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(InputEvent {
+            frame: self.frame,
+            time: self.time,
+            change,
+        });
+    }
+
+    /// Number of input reports recorded so far
+    #[inline]
+    pub fn get_frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Oldest first
+    #[inline]
+    pub fn events(&self) -> impl DoubleEndedIterator<Item = &InputEvent> + ExactSizeIterator {
+        self.events.iter()
+    }
+
+    /// The last `count` events, oldest first
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &InputEvent> {
+        self.events
+            .iter()
+            .skip(self.events.len().saturating_sub(count))
+    }
+
+    /// Statistics of buttons that were pressed at least once. Presses still going on are not
+    /// counted in the hold durations yet.
+    #[inline]
+    pub fn get_stats(&self, button: Button) -> Option<&ButtonStats> {
+        self.stats.get(&button)
+    }
+
+    /// Pressed buttons sorted by number of presses, most pressed first
+    pub fn all_stats(&self) -> Vec<(Button, &ButtonStats)> {
+        let mut stats = self
+            .stats
+            .iter()
+            .map(|(button, stats)| (*button, stats))
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|(button, stats)| (std::cmp::Reverse(stats.presses), button.to_string()));
+        stats
+    }
+
+    /// Forgets events and statistics, but keeps the state of the previous report so the next
+    /// one doesn't record everything as new
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.stats.clear();
+        let (frame, time) = (self.frame, self.time);
+        for &button in &self.previous_pressed {
+            self.stats.entry(button).or_default().pressed_since = Some((frame, time));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::Controller;
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    /// Records one report for every entry of `frames`, with the buttons pressed
+    fn record(history: &mut InputHistory, button_state: &mut ButtonState, frames: &[&[Button]]) {
+        for pressed in frames {
+            button_state.clear();
+            for &button in *pressed {
+                button_state.set(button, true).unwrap();
+            }
+            history.record(FRAME, button_state, None, None);
+        }
+    }
+
+    #[test]
+    fn presses_and_releases_are_recorded_once() {
+        let mut history = InputHistory::default();
+        let mut button_state = ButtonState::new(Controller::ProController);
+        record(
+            &mut history,
+            &mut button_state,
+            &[
+                &[],
+                &[Button::A],
+                &[Button::A, Button::B],
+                &[Button::B],
+                &[],
+            ],
+        );
+
+        let changes = history
+            .events()
+            .map(|event| (event.frame, event.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                (2, InputChange::Pressed(Button::A)),
+                (3, InputChange::Pressed(Button::B)),
+                (4, InputChange::Released(Button::A)),
+                (5, InputChange::Released(Button::B)),
+            ]
+        );
+        assert_eq!(history.get_frame(), 5);
+        assert_eq!(history.events().last().unwrap().time, FRAME * 5);
+    }
+
+    #[test]
+    fn hold_statistics_count_frames() {
+        let mut history = InputHistory::default();
+        let mut button_state = ButtonState::new(Controller::ProController);
+        let a: &[Button] = &[Button::A];
+        record(&mut history, &mut button_state, &[a, a, a, &[], a, &[]]);
+
+        let stats = history.get_stats(Button::A).unwrap();
+        assert_eq!(stats.presses, 2);
+        assert_eq!(stats.held_frames, 4);
+        assert_eq!(stats.held_time, FRAME * 4);
+        assert_eq!(stats.longest_hold_frames, 3);
+        assert!(history.get_stats(Button::B).is_none());
+    }
+
+    #[test]
+    fn oldest_events_are_evicted() {
+        let mut history = InputHistory::new(3);
+        let mut button_state = ButtonState::new(Controller::ProController);
+        let a: &[Button] = &[Button::A];
+        record(&mut history, &mut button_state, &[a, &[], a, &[]]);
+
+        assert_eq!(history.events().len(), 3);
+        assert_eq!(history.events().next().unwrap().frame, 2);
+        assert_eq!(history.last(1).next().unwrap().frame, 4);
+        // Statistics also cover the evicted events
+        assert_eq!(history.get_stats(Button::A).unwrap().presses, 2);
+
+        history.set_capacity(1);
+        assert_eq!(history.events().len(), 1);
+        assert_eq!(
+            history.events().next().unwrap().change,
+            InputChange::Released(Button::A)
+        );
+    }
+}
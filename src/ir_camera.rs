@@ -0,0 +1,562 @@
+use std::{error::Error, f32::consts::PI};
+
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::{
+    ir_processing::{self, ObjectConfig},
+    mcu::{pack_message, NO_RESPONSE_MESSAGE},
+};
+
+/// Pixels carried by a single IR image fragment
+pub const IR_FRAGMENT_SIZE: usize = 300;
+/// Number of fragments that may be sent ahead of the last acknowledged one before the unacknowledged
+/// ones are sent again
+const IR_ACK_WINDOW: usize = 8;
+const IR_IMAGE_REPORT_ID: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum IrMode {
+    Moments = 0x03,
+    Clustering = 0x06,
+    ImageTransfer = 0x07,
+    HandAnalysis = 0x08,
+}
+
+impl TryFrom<u8> for IrMode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x03 => Ok(Self::Moments),
+            0x06 => Ok(Self::Clustering),
+            0x07 => Ok(Self::ImageTransfer),
+            0x08 => Ok(Self::HandAnalysis),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Resolutions selectable through register 0x2E of page 0, values are the register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum IrResolution {
+    R320x240 = 0x00,
+    R160x120 = 0x50,
+    R80x60 = 0x64,
+    R40x30 = 0x69,
+}
+
+impl IrResolution {
+    pub fn from_register(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Self::R320x240),
+            0x50 => Some(Self::R160x120),
+            0x64 => Some(Self::R80x60),
+            0x69 => Some(Self::R40x30),
+            _ => None,
+        }
+    }
+
+    /// (width, height)
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self {
+            Self::R320x240 => (320, 240),
+            Self::R160x120 => (160, 120),
+            Self::R80x60 => (80, 60),
+            Self::R40x30 => (40, 30),
+        }
+    }
+
+    #[inline]
+    pub fn fragment_count(&self) -> usize {
+        let (width, height) = self.dimensions();
+        width * height / IR_FRAGMENT_SIZE
+    }
+}
+
+/// Sensor settings written by the console through the IR register commands
+#[derive(Debug, Clone)]
+pub struct IrConfig {
+    pub resolution: IrResolution,
+    /// Raw exposure register value, 31200 per millisecond
+    pub exposure: u16,
+    /// Bitmask of the disabled LED groups
+    pub leds: u8,
+    pub led_intensity: [u8; 2],
+    pub digital_gain: u16,
+    pub flip: u8,
+    /// Used by the clustering and hand analysis modes
+    pub objects: ObjectConfig,
+}
+
+impl Default for IrConfig {
+    fn default() -> Self {
+        Self {
+            resolution: IrResolution::R320x240,
+            exposure: 0x2490,
+            leds: 0,
+            led_intensity: [0x0F, 0x10],
+            digital_gain: 0x0100,
+            flip: 0,
+            objects: ObjectConfig::default(),
+        }
+    }
+}
+
+impl IrConfig {
+    pub fn write_register(&mut self, page: u8, register: u8, value: u8) {
+        match (page, register) {
+            (0x00, 0x2E) => match IrResolution::from_register(value) {
+                Some(resolution) => self.resolution = resolution,
+                None => warn!("IR: unknown resolution {:#x}", value),
+            },
+            (0x01, 0x30) => self.exposure = (self.exposure & 0xFF00) | value as u16,
+            (0x01, 0x31) => self.exposure = (self.exposure & 0x00FF) | ((value as u16) << 8),
+            (0x00, 0x10) => self.leds = value,
+            (0x00, 0x11) => self.led_intensity[0] = value,
+            (0x00, 0x12) => self.led_intensity[1] = value,
+            (0x01, 0x2E) => self.digital_gain = (self.digital_gain & 0xFF00) | value as u16,
+            (0x01, 0x2F) => {
+                self.digital_gain = (self.digital_gain & 0x00FF) | ((value as u16) << 8)
+            }
+            (0x00, 0x2D) => self.flip = value,
+            // Finalize config, denoise settings, etc. have no effect on emulated frames
+            _ => {}
+        }
+    }
+}
+
+/// 8 bit grayscale image
+#[derive(Debug, Clone)]
+pub struct IrImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl IrImage {
+    /// Loads a PNG or a binary/ASCII PGM file, colors are converted to luminance
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data = tokio::fs::read(source).await?;
+        let image = if data.starts_with(b"\x89PNG") {
+            Self::from_png(&data)?
+        } else {
+            Self::from_pgm(&data)?
+        };
+        info!(
+            "Loaded {}x{} IR image {}",
+            image.width, image.height, source
+        );
+        Ok(image)
+    }
+
+    pub fn from_png(data: &[u8]) -> Result<Self, IrImageError> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        buf.truncate(frame.buffer_size());
+        if frame.width == 0 || frame.height == 0 {
+            return Err(IrImageError::Empty);
+        }
+
+        let color_type = frame.color_type;
+        let pixels = buf
+            .chunks_exact(color_type.samples())
+            .map(|pixel| match color_type {
+                png::ColorType::Rgb | png::ColorType::Rgba => {
+                    ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000)
+                        as u8
+                }
+                _ => pixel[0],
+            })
+            .collect();
+        Ok(Self {
+            width: frame.width as usize,
+            height: frame.height as usize,
+            pixels,
+        })
+    }
+
+    pub fn from_pgm(data: &[u8]) -> Result<Self, IrImageError> {
+        let mut header = PgmTokenizer { data, pos: 0 };
+        let binary = match header.next_token()? {
+            b"P5" => true,
+            b"P2" => false,
+            _ => return Err(IrImageError::InvalidPgm("not a PGM file")),
+        };
+        let width = header.next_number()?;
+        let height = header.next_number()?;
+        if width == 0 || height == 0 {
+            return Err(IrImageError::Empty);
+        }
+        let max_value = header.next_number()?;
+        if max_value == 0 || max_value > 0xFFFF {
+            return Err(IrImageError::InvalidPgm("invalid maximum value"));
+        }
+
+        let samples = if binary {
+            // Exactly one whitespace character separates the header from the raster
+            let raster = &data[(header.pos + 1).min(data.len())..];
+            if max_value < 0x100 {
+                raster.iter().map(|&v| v as usize).collect::<Vec<_>>()
+            } else {
+                raster
+                    .chunks_exact(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]) as usize)
+                    .collect()
+            }
+        } else {
+            (0..(width * height))
+                .map(|_| header.next_number())
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if samples.len() < width * height {
+            return Err(IrImageError::InvalidPgm("not enough pixel data"));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels: samples[..(width * height)]
+                .iter()
+                .map(|&v| (v.min(max_value) * 0xFF / max_value) as u8)
+                .collect(),
+        })
+    }
+
+    /// Nearest neighbour scaling
+    pub fn resized(&self, width: usize, height: usize) -> Vec<u8> {
+        if self.width == width && self.height == height {
+            return self.pixels.clone();
+        }
+        (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| {
+                    self.pixels[(y * self.height / height) * self.width + x * self.width / width]
+                })
+            })
+            .collect()
+    }
+}
+
+/// Splits a PGM header into whitespace separated tokens, skipping comments
+struct PgmTokenizer<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PgmTokenizer<'a> {
+    fn next_token(&mut self) -> Result<&'a [u8], IrImageError> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(b'#') => {
+                    while self.data.get(self.pos).is_some_and(|&c| c != b'\n') {
+                        self.pos += 1
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(_) => break,
+                None => return Err(IrImageError::InvalidPgm("unexpected end of file")),
+            }
+        }
+        let start = self.pos;
+        while self
+            .data
+            .get(self.pos)
+            .is_some_and(|c| !c.is_ascii_whitespace())
+        {
+            self.pos += 1
+        }
+        Ok(&self.data[start..self.pos])
+    }
+
+    fn next_number(&mut self) -> Result<usize, IrImageError> {
+        std::str::from_utf8(self.next_token()?)
+            .ok()
+            .and_then(|token| token.parse().ok())
+            .ok_or(IrImageError::InvalidPgm("invalid number"))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum IrFrameSource {
+    /// A bright spot circling over a dark gradient
+    #[default]
+    Pattern,
+    /// Images shown one after another, one per frame
+    Images(Vec<IrImage>),
+}
+
+impl IrFrameSource {
+    pub fn frame(&self, frame_no: usize, resolution: IrResolution) -> Vec<u8> {
+        let (width, height) = resolution.dimensions();
+        match self {
+            Self::Images(images) if !images.is_empty() => {
+                images[frame_no % images.len()].resized(width, height)
+            }
+            Self::Images(_) => vec![0; width * height],
+            Self::Pattern => {
+                let angle = (frame_no % 60) as f32 / 60.0 * 2.0 * PI;
+                let spot_x = width as f32 * (0.5 + 0.3 * angle.cos());
+                let spot_y = height as f32 * (0.5 + 0.3 * angle.sin());
+                let radius = height as f32 / 8.0;
+                (0..height)
+                    .flat_map(|y| {
+                        (0..width).map(move |x| {
+                            let distance =
+                                ((x as f32 - spot_x).powi(2) + (y as f32 - spot_y).powi(2)).sqrt();
+                            if distance < radius {
+                                0xFF
+                            } else {
+                                (x * 0x40 / width) as u8
+                            }
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Image transfer state of the IR camera
+#[derive(Debug, Clone, Default)]
+pub struct IrCamera {
+    pub config: IrConfig,
+    pub source: IrFrameSource,
+    mode: Option<IrMode>,
+    frame_no: usize,
+    frame: Vec<u8>,
+    next_fragment: usize,
+    acked_fragment: Option<usize>,
+}
+
+impl IrCamera {
+    /// Stops streaming, used when the MCU enters or leaves IR mode
+    pub fn reset(&mut self) {
+        self.mode = None;
+        self.frame.clear();
+        self.next_fragment = 0;
+        self.acked_fragment = None;
+    }
+
+    #[inline]
+    pub fn get_mode(&self) -> Option<IrMode> {
+        self.mode
+    }
+
+    /// Handles the "set IR mode" config command.
+    /// Arguments: mode, last fragment number, MCU firmware major and minor version
+    pub fn set_mode(&mut self, args: &[u8]) {
+        self.reset();
+        match IrMode::try_from(args[0]) {
+            Ok(mode) => {
+                if args[1] as usize + 1 != self.config.resolution.fragment_count() {
+                    info!(
+                        "IR: console expects {} fragments, current resolution has {}",
+                        args[1] as usize + 1,
+                        self.config.resolution.fragment_count()
+                    );
+                }
+                info!("IR: set mode {:?}", mode);
+                self.mode = Some(mode);
+            }
+            Err(mode) => warn!("IR: unsupported mode {:#x}", mode),
+        }
+    }
+
+    /// Handles the "write IR registers" config command.
+    /// Arguments: register count, followed by (page, register, value) triples
+    pub fn write_registers(&mut self, args: &[u8]) {
+        let count = args[0] as usize;
+        for register in args[1..].chunks_exact(3).take(count) {
+            self.config
+                .write_register(register[0], register[1], register[2]);
+        }
+        // A changed resolution invalidates the frame being sent
+        self.frame.clear();
+    }
+
+    /// Handles the IR acknowledgement sent with output report 0x11.
+    /// Arguments: 0x00, resend request flag, fragment to resend, acknowledged fragment
+    pub fn process_ack(&mut self, args: &[u8]) {
+        if args[1] == 0x01 {
+            self.next_fragment = args[2] as usize;
+        } else {
+            self.acked_fragment = Some(args[3] as usize);
+        }
+    }
+
+    /// MCU data of the next input report: the next image fragment in image transfer mode, the
+    /// results for a new frame in the processing modes, an empty message otherwise
+    pub fn get_data(&mut self) -> Vec<u8> {
+        match self.mode {
+            Some(IrMode::ImageTransfer) => self.get_image_fragment(),
+            Some(mode) => self.get_processed_frame(mode),
+            None => NO_RESPONSE_MESSAGE.to_vec(),
+        }
+    }
+
+    /// The processing modes fit their results for a whole frame into a single report, so no
+    /// acknowledgement is needed
+    fn get_processed_frame(&mut self, mode: IrMode) -> Vec<u8> {
+        let (width, height) = self.config.resolution.dimensions();
+        let frame = self.source.frame(self.frame_no, self.config.resolution);
+        let payload = match mode {
+            IrMode::Moments => ir_processing::moments(&frame, width, height)
+                .iter()
+                .flat_map(|moment| moment.as_bytes())
+                .collect(),
+            IrMode::Clustering => {
+                let clusters = ir_processing::clusters(&frame, width, height, &self.config.objects);
+                let mut payload = vec![clusters.len() as u8];
+                payload.extend(clusters.iter().flat_map(|cluster| cluster.as_bytes()));
+                payload
+            }
+            IrMode::HandAnalysis => {
+                ir_processing::silhouette(&frame, width, height, &self.config.objects).as_bytes()
+            }
+            IrMode::ImageTransfer => unreachable!(),
+        };
+
+        let mut message = vec![0; 10];
+        message[0] = IR_IMAGE_REPORT_ID;
+        message[1] = mode as u8;
+        message[3] = self.frame_no as u8;
+        message.extend(payload);
+        self.frame_no += 1;
+        pack_message(&message, None, None, None)
+    }
+
+    fn get_image_fragment(&mut self) -> Vec<u8> {
+        let last_fragment = self.config.resolution.fragment_count() - 1;
+        if self.frame.is_empty() || self.acked_fragment == Some(last_fragment) {
+            self.frame = self.source.frame(self.frame_no, self.config.resolution);
+            self.frame_no += 1;
+            self.next_fragment = 0;
+            self.acked_fragment = None;
+        }
+
+        let first_unacked = self.acked_fragment.map_or(0, |acked| acked + 1);
+        if self.next_fragment > last_fragment || self.next_fragment >= first_unacked + IR_ACK_WINDOW
+        {
+            self.next_fragment = first_unacked;
+        }
+
+        let fragment = self.next_fragment;
+        self.next_fragment += 1;
+        let mut message = vec![0; 10 + IR_FRAGMENT_SIZE];
+        message[0] = IR_IMAGE_REPORT_ID;
+        message[3] = fragment as u8;
+        message[10..].copy_from_slice(
+            &self.frame[(fragment * IR_FRAGMENT_SIZE)..((fragment + 1) * IR_FRAGMENT_SIZE)],
+        );
+        pack_message(&message, None, None, None)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum IrImageError {
+    #[error("Invalid PGM file: {0}")]
+    InvalidPgm(&'static str),
+    #[error("Invalid PNG file: {0}")]
+    Png(#[from] png::DecodingError),
+    #[error("Image has no pixels")]
+    Empty,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fragment numbers of the next `count` reports
+    fn fragments(camera: &mut IrCamera, count: usize) -> Vec<u8> {
+        (0..count).map(|_| camera.get_data()[3]).collect()
+    }
+
+    #[test]
+    fn ascii_pgm_with_comments_is_scaled_to_8_bits() {
+        let image = IrImage::from_pgm(b"P2\n# comment\n3 2 # size\n4\n0 1 2\n3 4 9\n").unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixels, [0, 63, 127, 191, 255, 255]);
+    }
+
+    #[test]
+    fn binary_pgm_has_8_or_16_bit_samples() {
+        let image = IrImage::from_pgm(b"P5 2 1 255\n\x10\x20").unwrap();
+        assert_eq!(image.pixels, [0x10, 0x20]);
+        let image = IrImage::from_pgm(b"P5\n2 1\n65535\n\xFF\xFF\x80\x00").unwrap();
+        assert_eq!(image.pixels, [0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn invalid_pgm_is_rejected() {
+        for (data, error) in [
+            (&b"P5 2 2 255\n\x00\x00\x00"[..], "not enough pixel data"),
+            (b"P2 2 1 255\n1", "unexpected end of file"),
+            (b"P6 1 1 255\n\x00\x00\x00", "not a PGM file"),
+            (b"P5 1 1 0\n\x00", "invalid maximum value"),
+        ] {
+            assert!(
+                matches!(IrImage::from_pgm(data), Err(IrImageError::InvalidPgm(why)) if why == error),
+                "{}",
+                error
+            );
+        }
+        assert!(matches!(
+            IrImage::from_pgm(b"P2 0 1 255\n"),
+            Err(IrImageError::Empty)
+        ));
+    }
+
+    #[test]
+    fn fragments_cover_the_frame() {
+        assert_eq!(IrResolution::R320x240.fragment_count(), 256);
+        assert_eq!(IrResolution::R160x120.fragment_count(), 64);
+        assert_eq!(IrResolution::R80x60.fragment_count(), 16);
+        assert_eq!(IrResolution::R40x30.fragment_count(), 4);
+    }
+
+    #[test]
+    fn unacknowledged_fragments_are_sent_again() {
+        let mut camera = IrCamera::default();
+        camera.set_mode(&[IrMode::ImageTransfer as u8, 0xFF, 0, 0]);
+        assert_eq!(fragments(&mut camera, 8), [0, 1, 2, 3, 4, 5, 6, 7]);
+        camera.process_ack(&[0, 0, 0, 7]);
+        assert_eq!(fragments(&mut camera, 9), [8, 9, 10, 11, 12, 13, 14, 15, 8]);
+        // Without an acknowledgement, the window starts over at the first unacknowledged fragment
+        assert_eq!(fragments(&mut camera, 8), [9, 10, 11, 12, 13, 14, 15, 8]);
+        camera.process_ack(&[0, 0, 0, 10]);
+        camera.process_ack(&[0, 0x01, 12, 0]);
+        assert_eq!(fragments(&mut camera, 2), [12, 13]);
+    }
+
+    #[test]
+    fn acknowledging_the_last_fragment_starts_a_new_frame() {
+        let image = IrImage {
+            width: 40,
+            height: 30,
+            pixels: (0..1200).map(|i| (i / IR_FRAGMENT_SIZE) as u8).collect(),
+        };
+        let mut camera = IrCamera {
+            source: IrFrameSource::Images(vec![image]),
+            ..Default::default()
+        };
+        camera.write_registers(&[1, 0x00, 0x2E, IrResolution::R40x30 as u8]);
+        camera.set_mode(&[IrMode::ImageTransfer as u8, 3, 0, 0]);
+        for fragment in 0..4 {
+            let data = camera.get_data();
+            assert_eq!(data[3], fragment);
+            assert!(data[10..(10 + IR_FRAGMENT_SIZE)]
+                .iter()
+                .all(|&p| p == fragment));
+        }
+        assert_eq!(fragments(&mut camera, 1), [0]);
+        camera.process_ack(&[0, 0, 0, 3]);
+        assert_eq!(fragments(&mut camera, 2), [0, 1]);
+        assert_eq!(camera.frame_no, 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! Computed IR sensor outputs for the modes that don't transfer the image itself.
+//!
+//! Payload encoding: intensities are u8.8 fixed point, coordinates are u12.4 fixed point in
+//! pixels of the configured resolution, all little endian.
+
+use std::cmp::Reverse;
+
+/// Moments mode splits the image into a grid of blocks
+pub const MOMENT_BLOCKS_H: usize = 8;
+pub const MOMENT_BLOCKS_V: usize = 6;
+pub const MAX_CLUSTERS: usize = 16;
+pub const MAX_SILHOUETTE_POINTS: usize = 32;
+
+#[inline]
+fn fixed_intensity(value: f32) -> [u8; 2] {
+    ((value * 256.0) as u16).to_le_bytes()
+}
+
+#[inline]
+fn fixed_coordinate(value: f32) -> [u8; 2] {
+    ((value * 16.0) as u16).to_le_bytes()
+}
+
+/// Thresholds for the modes that look for bright objects
+#[derive(Debug, Clone)]
+pub struct ObjectConfig {
+    /// Pixels at or above this intensity belong to objects
+    pub intensity_min: u8,
+    pub pixel_count_min: usize,
+    pub pixel_count_max: usize,
+}
+
+impl Default for ObjectConfig {
+    fn default() -> Self {
+        Self {
+            intensity_min: 150,
+            pixel_count_min: 3,
+            pixel_count_max: 320 * 240,
+        }
+    }
+}
+
+/// Statistics of one block in moments mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Moment {
+    pub average_intensity: f32,
+    pub centroid_x: f32,
+    pub centroid_y: f32,
+}
+
+impl Moment {
+    pub fn as_bytes(&self) -> [u8; 6] {
+        let [i0, i1] = fixed_intensity(self.average_intensity);
+        let [x0, x1] = fixed_coordinate(self.centroid_x);
+        let [y0, y1] = fixed_coordinate(self.centroid_y);
+        [i0, i1, x0, x1, y0, y1]
+    }
+}
+
+/// Intensity weighted centroid and average intensity of each block, row by row
+pub fn moments(frame: &[u8], width: usize, height: usize) -> Vec<Moment> {
+    let block_width = width / MOMENT_BLOCKS_H;
+    let block_height = height / MOMENT_BLOCKS_V;
+    (0..MOMENT_BLOCKS_V)
+        .flat_map(|block_y| (0..MOMENT_BLOCKS_H).map(move |block_x| (block_x, block_y)))
+        .map(|(block_x, block_y)| {
+            let (mut sum, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+            for y in (block_y * block_height)..((block_y + 1) * block_height) {
+                for x in (block_x * block_width)..((block_x + 1) * block_width) {
+                    let value = frame[y * width + x] as f32;
+                    sum += value;
+                    sum_x += value * x as f32;
+                    sum_y += value * y as f32;
+                }
+            }
+            let (centroid_x, centroid_y) = if sum > 0.0 {
+                (sum_x / sum, sum_y / sum)
+            } else {
+                (
+                    (block_x as f32 + 0.5) * block_width as f32,
+                    (block_y as f32 + 0.5) * block_height as f32,
+                )
+            };
+            Moment {
+                average_intensity: sum / (block_width * block_height) as f32,
+                centroid_x,
+                centroid_y,
+            }
+        })
+        .collect()
+}
+
+/// A connected bright object in clustering mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub average_intensity: f32,
+    pub pixel_count: usize,
+    pub centroid_x: f32,
+    pub centroid_y: f32,
+    /// (x, y, width, height)
+    pub bounding_box: (usize, usize, usize, usize),
+    pixels: Vec<usize>,
+}
+
+impl Cluster {
+    pub fn as_bytes(&self) -> [u8; 16] {
+        let mut result = [0; 16];
+        result[0..2].copy_from_slice(&fixed_intensity(self.average_intensity));
+        result[2..4].copy_from_slice(&(self.pixel_count.min(0xFFFF) as u16).to_le_bytes());
+        result[4..6].copy_from_slice(&fixed_coordinate(self.centroid_x));
+        result[6..8].copy_from_slice(&fixed_coordinate(self.centroid_y));
+        let (x, y, w, h) = self.bounding_box;
+        for (i, value) in [x, y, w, h].into_iter().enumerate() {
+            result[(8 + i * 2)..(10 + i * 2)].copy_from_slice(&(value as u16).to_le_bytes());
+        }
+        result
+    }
+}
+
+/// 4-connected objects matching `config`, largest first, at most `MAX_CLUSTERS`
+pub fn clusters(frame: &[u8], width: usize, height: usize, config: &ObjectConfig) -> Vec<Cluster> {
+    let mut visited = vec![false; frame.len()];
+    let mut result = vec![];
+    for start in 0..frame.len() {
+        if visited[start] || frame[start] < config.intensity_min {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![start];
+        let mut pixels = vec![];
+        while let Some(pos) = stack.pop() {
+            pixels.push(pos);
+            let (x, y) = (pos % width, pos / width);
+            let neighbours = [
+                (x > 0).then(|| pos - 1),
+                (x + 1 < width).then(|| pos + 1),
+                (y > 0).then(|| pos - width),
+                (y + 1 < height).then(|| pos + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if !visited[neighbour] && frame[neighbour] >= config.intensity_min {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+        if (config.pixel_count_min..=config.pixel_count_max).contains(&pixels.len()) {
+            result.push(cluster_from_pixels(frame, width, pixels));
+        }
+    }
+    result.sort_by_key(|cluster| Reverse(cluster.pixel_count));
+    result.truncate(MAX_CLUSTERS);
+    result
+}
+
+fn cluster_from_pixels(frame: &[u8], width: usize, pixels: Vec<usize>) -> Cluster {
+    let (mut sum, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for &pos in &pixels {
+        let (x, y) = (pos % width, pos / width);
+        let value = frame[pos] as f32;
+        sum += value;
+        sum_x += value * x as f32;
+        sum_y += value * y as f32;
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    Cluster {
+        average_intensity: sum / pixels.len() as f32,
+        pixel_count: pixels.len(),
+        centroid_x: sum_x / sum,
+        centroid_y: sum_y / sum,
+        bounding_box: (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1),
+        pixels,
+    }
+}
+
+/// Outline of the largest object (assumed to be the hand) in hand analysis mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct Silhouette {
+    /// Outline points ordered by angle around the centroid, as (x, y)
+    pub points: Vec<(f32, f32)>,
+}
+
+impl Silhouette {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut result = vec![self.points.len() as u8];
+        for (x, y) in &self.points {
+            result.extend_from_slice(&fixed_coordinate(*x));
+            result.extend_from_slice(&fixed_coordinate(*y));
+        }
+        result
+    }
+}
+
+pub fn silhouette(frame: &[u8], width: usize, height: usize, config: &ObjectConfig) -> Silhouette {
+    let cluster = match clusters(frame, width, height, config).into_iter().next() {
+        Some(cluster) => cluster,
+        None => return Silhouette { points: vec![] },
+    };
+    let is_inside = |x: isize, y: isize| {
+        x >= 0
+            && y >= 0
+            && (x as usize) < width
+            && (y as usize) < height
+            && frame[y as usize * width + x as usize] >= config.intensity_min
+    };
+
+    let mut outline = cluster
+        .pixels
+        .iter()
+        .map(|&pos| ((pos % width) as isize, (pos / width) as isize))
+        .filter(|&(x, y)| {
+            !(is_inside(x - 1, y)
+                && is_inside(x + 1, y)
+                && is_inside(x, y - 1)
+                && is_inside(x, y + 1))
+        })
+        .map(|(x, y)| (x as f32, y as f32))
+        .collect::<Vec<_>>();
+    let angle = |&(x, y): &(f32, f32)| (y - cluster.centroid_y).atan2(x - cluster.centroid_x);
+    outline.sort_by(|a, b| angle(a).total_cmp(&angle(b)));
+
+    let step = (outline.len() as f32 / MAX_SILHOUETTE_POINTS as f32).max(1.0);
+    Silhouette {
+        points: (0..outline.len().min(MAX_SILHOUETTE_POINTS))
+            .map(|i| outline[(i as f32 * step) as usize])
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 80;
+    const HEIGHT: usize = 60;
+
+    /// Black 80x60 frame with a 10x10 block of intensity 200 at (20, 10) and a bright pair of
+    /// pixels too small to be an object at (70, 50)
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![0; WIDTH * HEIGHT];
+        for y in 10..20 {
+            frame[(y * WIDTH + 20)..(y * WIDTH + 30)].fill(200);
+        }
+        frame[50 * WIDTH + 70] = 255;
+        frame[50 * WIDTH + 71] = 255;
+        frame
+    }
+
+    #[test]
+    fn moments_average_each_block() {
+        let moments = moments(&frame(), WIDTH, HEIGHT);
+        assert_eq!(moments.len(), MOMENT_BLOCKS_H * MOMENT_BLOCKS_V);
+        let block = moments[MOMENT_BLOCKS_H + 2];
+        assert_eq!(
+            block,
+            Moment {
+                average_intensity: 200.0,
+                centroid_x: 24.5,
+                centroid_y: 14.5,
+            }
+        );
+        assert_eq!(block.as_bytes(), [0x00, 0xC8, 0x88, 0x01, 0xE8, 0x00]);
+        // Dark blocks have their center as centroid
+        assert_eq!(
+            moments[0],
+            Moment {
+                average_intensity: 0.0,
+                centroid_x: 5.0,
+                centroid_y: 5.0,
+            }
+        );
+        assert_eq!(moments[5 * MOMENT_BLOCKS_H + 7].average_intensity, 5.1);
+    }
+
+    #[test]
+    fn bright_block_is_one_cluster() {
+        let clusters = clusters(&frame(), WIDTH, HEIGHT, &ObjectConfig::default());
+        assert_eq!(clusters.len(), 1);
+        let cluster = &clusters[0];
+        assert_eq!(cluster.pixel_count, 100);
+        assert_eq!(cluster.average_intensity, 200.0);
+        assert_eq!((cluster.centroid_x, cluster.centroid_y), (24.5, 14.5));
+        assert_eq!(cluster.bounding_box, (20, 10, 10, 10));
+    }
+
+    #[test]
+    fn silhouette_follows_the_outline() {
+        let config = ObjectConfig::default();
+        let outline = silhouette(&frame(), WIDTH, HEIGHT, &config);
+        // The outline has 36 pixels
+        assert_eq!(outline.points.len(), MAX_SILHOUETTE_POINTS);
+        assert!(outline
+            .points
+            .iter()
+            .all(|&(x, y)| x == 20.0 || x == 29.0 || y == 10.0 || y == 19.0));
+
+        let black = silhouette(&[0; WIDTH * HEIGHT], WIDTH, HEIGHT, &config);
+        assert!(black.points.is_empty());
+        assert_eq!(black.as_bytes(), [0]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    borrow::Cow,
+    io::Write,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+};
+
+use log::warn;
+use rustyline::{
+    completion::Completer, config::Config, error::ReadlineError, highlight::Highlighter,
+    hint::Hinter, history::DefaultHistory, validate::Validator, CompletionType, Context, Editor,
+    Helper,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Created in the home directory
+pub const HISTORY_FILE_NAME: &str = ".joycontrol-rs_history";
+const PROMPT: &str = "cmd >> ";
+
+const STICK_SIDES: [&str; 4] = ["l", "left", "r", "right"];
+const STICK_DIRECTIONS: [&str; 15] = [
+    "center",
+    "up",
+    "down",
+    "left",
+    "right",
+    "up_left",
+    "up_right",
+    "down_left",
+    "down_right",
+    "h",
+    "horizontal",
+    "v",
+    "vertical",
+    "angle",
+    "percent",
+];
+const NFC_ACTIONS: [&str; 5] = ["list", "load", "remove", "cycle", "restore"];
+const HISTORY_ACTIONS: [&str; 2] = ["stats", "clear"];
+const BUTTON_COMMANDS: [&str; 3] = ["turbo", "toggle", "hold"];
+
+pub fn default_history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME))
+}
+
+/// Words offered by tab completion, kept up to date by the CLI
+#[derive(Debug, Clone, Default)]
+pub struct CompletionWords {
+    pub commands: Vec<String>,
+    /// Button names and aliases
+    pub buttons: Vec<String>,
+    pub amiibos: Vec<String>,
+    pub profiles: Vec<String>,
+}
+
+impl CompletionWords {
+    /// Candidates for the word at the end of `line`, and where that word starts
+    fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let segment_start = line.rfind("&&").map_or(0, |pos| pos + 2);
+        let segment = &line[segment_start..];
+        let word_start = segment.rfind(char::is_whitespace).map_or(0, |pos| pos + 1);
+        let (previous, word) = segment.split_at(word_start);
+        let previous = previous.split_whitespace().collect::<Vec<_>>();
+        let start = segment_start + word_start;
+        let strs = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+
+        let candidates: Vec<String> = match previous.as_slice() {
+            [] => {
+                let mut candidates = self.commands.clone();
+                candidates.extend(self.buttons.iter().cloned());
+                candidates
+            }
+            ["help"] => self.commands.clone(),
+            ["stick"] => strs(&STICK_SIDES),
+            ["stick", _] => strs(&STICK_DIRECTIONS),
+            ["nfc"] => strs(&NFC_ACTIONS),
+            ["nfc", "load"] => {
+                // Names with spaces are quoted, so they are matched before quoting
+                let unquoted = word.trim_start_matches(['\'', '"']);
+                let candidates = Self::matching(self.amiibos.clone(), unquoted);
+                return (
+                    start,
+                    candidates
+                        .iter()
+                        .filter_map(|name| shlex::try_quote(name).ok().map(Cow::into_owned))
+                        .collect(),
+                );
+            }
+            ["profile"] => {
+                let mut candidates = self.profiles.clone();
+                candidates.push("off".into());
+                candidates
+            }
+            ["history"] => strs(&HISTORY_ACTIONS),
+            [command] if BUTTON_COMMANDS.contains(command) => self.buttons.clone(),
+            ["combo", ..] => return self.complete_combo(start, word),
+            _ => vec![],
+        };
+        (start, Self::matching(candidates, word))
+    }
+
+    /// Completes the last input of a combo step, e.g. "a+ls.do"
+    fn complete_combo(&self, start: usize, word: &str) -> (usize, Vec<String>) {
+        let input_start = word.rfind('+').map_or(0, |pos| pos + 1);
+        let input = &word[input_start..];
+        let candidates = match input.get(..3) {
+            Some(stick @ ("ls." | "rs.")) => STICK_DIRECTIONS
+                .iter()
+                .filter(|direction| {
+                    !["h", "horizontal", "v", "vertical", "angle", "percent"].contains(direction)
+                })
+                .map(|direction| format!("{}{}", stick, direction))
+                .collect(),
+            _ => {
+                let mut candidates = self.buttons.clone();
+                candidates.extend(["ls.".into(), "rs.".into()]);
+                candidates
+            }
+        };
+        (start + input_start, Self::matching(candidates, input))
+    }
+
+    fn matching(mut candidates: Vec<String>, word: &str) -> Vec<String> {
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+struct CliHelper {
+    words: Arc<Mutex<CompletionWords>>,
+}
+
+impl Completer for CliHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.words.lock().unwrap().complete(&line[..pos]))
+    }
+}
+
+impl Hinter for CliHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CliHelper {}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
+
+/// Reads lines on its own thread, since reading from the terminal blocks. A line is only read
+/// when requested, so the prompt doesn't show up while a command is still printing.
+pub struct LineEditor {
+    requests: mpsc::Sender<()>,
+    lines: Receiver<String>,
+    /// A line was requested but not received yet
+    pending: bool,
+}
+
+impl LineEditor {
+    /// The history is loaded from and saved to `history_file` if given
+    pub fn spawn(words: Arc<Mutex<CompletionWords>>, history_file: Option<PathBuf>) -> Self {
+        let (requests, request_rx) = mpsc::channel::<()>();
+        let (tx, lines) = channel(1);
+        std::thread::spawn(move || {
+            let config = Config::builder()
+                .auto_add_history(true)
+                .completion_type(CompletionType::List)
+                .build();
+            let mut editor = match Editor::<CliHelper, DefaultHistory>::with_config(config) {
+                Ok(editor) => editor,
+                Err(why) => {
+                    warn!(
+                        "Couldn't start the line editor, falling back to stdin: {}",
+                        why
+                    );
+                    for _ in request_rx {
+                        let mut buf = String::new();
+                        print!("{}", PROMPT);
+                        let _ = std::io::stdout().flush();
+                        let line = match std::io::stdin().read_line(&mut buf) {
+                            Ok(0) | Err(_) => "exit".into(),
+                            Ok(_) => buf,
+                        };
+                        if tx.blocking_send(line).is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+            };
+            editor.set_helper(Some(CliHelper { words }));
+            if let Some(history_file) = &history_file {
+                // Missing on the first start
+                let _ = editor.load_history(history_file);
+            }
+
+            for _ in request_rx {
+                let line = match editor.readline(PROMPT) {
+                    Ok(line) => line,
+                    Err(ReadlineError::Interrupted) => String::new(),
+                    Err(ReadlineError::Eof) => "exit".into(),
+                    Err(why) => {
+                        warn!("Couldn't read line: {}", why);
+                        "exit".into()
+                    }
+                };
+                if let Some(history_file) = &history_file {
+                    if let Err(why) = editor.save_history(history_file) {
+                        warn!("Couldn't save the CLI history: {}", why);
+                    }
+                }
+                if tx.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            lines,
+            pending: false,
+        }
+    }
+
+    /// None if the editor thread stopped. Cancel safe, a line requested by a cancelled call is
+    /// returned by the next one.
+    pub async fn read_line(&mut self) -> Option<String> {
+        if !self.pending {
+            self.requests.send(()).ok()?;
+            self.pending = true;
+        }
+        let line = self.lines.recv().await;
+        self.pending = false;
+        line
+    }
+}
@@ -1,20 +1,175 @@
-use log::info;
+use std::{error::Error, path::PathBuf};
+
+use amiibo::AmiiboKeys;
+use amiibo_library::AmiiboLibrary;
+use button_mapping::ButtonProfiles;
+use cli::ControllerCli;
+use controller::Controller;
+use controller_state::ControllerState;
+use log::{info, warn};
 use log4rs::init_file;
+use memory::FlashMemory;
+use rhai_script::RhaiScript;
+use script::Script;
+use stick_noise::{StickNoise, StickNoiseConfig};
+use stick_processing::StickProcessor;
 
+mod amiibo;
+mod amiibo_library;
+mod button_mapping;
 mod button_state;
 mod cli;
 mod controller;
 mod controller_state;
 mod device;
+mod firmware_update;
+mod input_history;
+mod ir_camera;
+mod ir_processing;
+mod line_editor;
 mod mcu;
 mod memory;
 mod nfc_tag;
 mod protocol;
+mod rhai_script;
+mod script;
 mod stick_calibration;
+mod stick_motion;
+mod stick_noise;
+mod stick_processing;
 mod stick_state;
+mod transport;
+
+/// Options of the interactive CLI
+#[derive(Debug, Default)]
+struct Options {
+    /// `--amiibo-library <dir>`, directory indexed for the nfc command
+    amiibo_library: Option<PathBuf>,
+    /// `--amiibo-keys <file>`, used to read the nicknames of the library's amiibo
+    amiibo_keys: Option<String>,
+    /// `--button-profiles <file>`, profiles for the profile command
+    button_profiles: Option<String>,
+    /// `--stick-processing <file>`, deadzones and response curve of both sticks
+    stick_processing: Option<String>,
+    /// `--stick-noise <file>`, jitter and drift added to the reports of both sticks
+    stick_noise: Option<String>,
+    /// `run <file>`, runs the script instead of the interactive CLI
+    script: Option<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--amiibo-library" => options.amiibo_library = Some(value()?.into()),
+                "--amiibo-keys" => options.amiibo_keys = Some(value()?),
+                "--button-profiles" => options.button_profiles = Some(value()?),
+                "--stick-processing" => options.stick_processing = Some(value()?),
+                "--stick-noise" => options.stick_noise = Some(value()?),
+                "run" => options.script = Some(value()?),
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Runs a .rhai file as a Rhai script, anything else as a command script
+async fn run_script(
+    cli: &mut ControllerCli<'_>,
+    file: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if file.ends_with(".rhai") {
+        let script = RhaiScript::load(file, cli.get_controller_state_mut()).await?;
+        cli.run_rhai_script(script).await?;
+    } else {
+        Script::load(file).await?.run(cli).await?;
+    }
+    Ok(())
+}
+
+/// Runs the interactive CLI, or the script of `run <file>`, on a Pro Controller with the default
+/// SPI flash
+async fn run_cli(options: Options) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let amiibo_library = if let Some(directory) = &options.amiibo_library {
+        let keys = match &options.amiibo_keys {
+            Some(file) => Some(AmiiboKeys::load(file).await?),
+            None => None,
+        };
+        let library = AmiiboLibrary::index(directory, keys.as_ref()).await?;
+        info!(
+            "Indexed {} amiibo in {}",
+            library.entries().len(),
+            directory.display()
+        );
+        Some(library)
+    } else {
+        None
+    };
+
+    let button_profiles = match &options.button_profiles {
+        Some(file) => Some(ButtonProfiles::load(file).await?),
+        None => None,
+    };
+    let stick_processor = match &options.stick_processing {
+        Some(file) => Some(StickProcessor::load(file).await?),
+        None => None,
+    };
+    let stick_noise = match &options.stick_noise {
+        Some(file) => Some(StickNoiseConfig::load(file).await?),
+        None => None,
+    };
 
-fn main() {
+    let mut controller_state = ControllerState::new(
+        Controller::ProController,
+        Some(FlashMemory::new(None, None, None)?),
+    );
+    let sticks = [
+        &mut controller_state.l_stick_state,
+        &mut controller_state.r_stick_state,
+    ];
+    for stick in sticks.into_iter().flatten() {
+        stick.set_processor(stick_processor.clone());
+        stick.set_noise(stick_noise.clone().map(StickNoise::new));
+    }
+    warn!("Not connected to a console, input reports are not sent anywhere");
+    let mut cli = ControllerCli::new(&mut controller_state);
+    if let Some(amiibo_library) = amiibo_library {
+        cli.set_amiibo_library(amiibo_library);
+    }
+    if let Some(button_profiles) = button_profiles {
+        cli.set_button_profiles(button_profiles);
+    }
+    match &options.script {
+        Some(file) => run_script(&mut cli, file)
+            .await
+            .map_err(|why| format!("{}: {}", file, why).into()),
+        None => {
+            cli.run().await;
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
     init_file("log_config.yaml", Default::default()).unwrap();
     info!("Starting up!");
-    println!("Hello, world!");
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match Options::parse(&args) {
+        Ok(options) => run_cli(options).await,
+        Err(why) => Err(why.into()),
+    };
+    if let Err(why) = result {
+        eprintln!("{}", why);
+        std::process::exit(1);
+    }
 }
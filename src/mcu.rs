@@ -2,20 +2,48 @@ use bytes::Bytes;
 use crc::{Crc, CRC_8_SMBUS};
 use lazy_static::lazy_static;
 use log::{error, info, warn};
+use std::collections::VecDeque;
 use uuid::Uuid;
 
-use crate::{controller_state::ControllerState, nfc_tag::NFCTag};
+use crate::{
+    amiibo::AmiiboKeys,
+    controller_state::ControllerState,
+    firmware_update::{FirmwareUpdate, FirmwareVersion, UpdateOperation, UpdateResult},
+    ir_camera::IrCamera,
+    nfc_tag::NFCTag,
+};
 
 lazy_static! {
     pub static ref REMOVE_AMIIBO: NFCTag = NFCTag::new(&[0; 540], None, None);
     pub static ref NO_RESPONSE_MESSAGE: Vec<u8> = pack_message(&[0xFF], None, None, None);
 }
 
+/// Length of a packed MCU message, one is sent with every 0x31 input report
+pub const MCU_MESSAGE_LEN: usize = 313;
 const MAX_RESPONSE_QUEUE_LEN: usize = 4;
+/// Number of polls the removal of a tag is reported for before a new one is shown
+const ACTIVE_REMOVE_POLLS: u32 = 10;
 
-const SET_POWER_VALUES: [MCUPowerState; 2] = [MCUPowerState::Suspended, MCUPowerState::Ready];
-const SET_CONFIG_VALUES: [MCUPowerState; 2] = [MCUPowerState::Ready, MCUPowerState::ConfiguredNFC];
-const GET_STATUS_VALUES: [MCUPowerState; 2] = [MCUPowerState::Ready, MCUPowerState::ConfiguredNFC];
+const SET_POWER_VALUES: [MCUPowerState; 3] = [
+    MCUPowerState::Suspended,
+    MCUPowerState::Ready,
+    MCUPowerState::ReadyUpdate,
+];
+const SET_CONFIG_VALUES: [MCUPowerState; 4] = [
+    MCUPowerState::Ready,
+    MCUPowerState::ConfiguredNFC,
+    MCUPowerState::ConfiguredIR,
+    MCUPowerState::ConfiguredUpdate,
+];
+const GET_STATUS_VALUES: [MCUPowerState; 5] = [
+    MCUPowerState::Ready,
+    MCUPowerState::ReadyUpdate,
+    MCUPowerState::ConfiguredNFC,
+    MCUPowerState::ConfiguredIR,
+    MCUPowerState::ConfiguredUpdate,
+];
+
+const FIRMWARE_UPDATE_REPORT_ID: u8 = 0x06;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -24,8 +52,8 @@ pub enum MCUPowerState {
     Ready = 0x01,
     ReadyUpdate = 0x02,
     ConfiguredNFC = 0x04,
-    // ConfiguredIR = 0x05,
-    // COnfiguredUpdate = 0x06
+    ConfiguredIR = 0x05,
+    ConfiguredUpdate = 0x06,
 }
 
 pub fn mcu_crc(data: &[u8]) -> u8 {
@@ -45,6 +73,47 @@ pub enum NFCState {
     PollAgain = 0x09,
 }
 
+/// Kind of a queued response. IR image fragments aren't queued, the camera sends them whenever
+/// the queue is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MCUPacketKind {
+    Status,
+    FirmwareUpdate,
+}
+
+/// A packed MCU message waiting to be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MCUPacket {
+    pub kind: MCUPacketKind,
+    data: Vec<u8>,
+}
+
+impl MCUPacket {
+    /// `data` has to be a packed message, see `pack_message`
+    pub fn new(kind: MCUPacketKind, data: Vec<u8>) -> Self {
+        debug_assert_eq!(data.len(), MCU_MESSAGE_LEN);
+        Self { kind, data }
+    }
+
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Counters of the response queue since the MCU was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponseQueueStats {
+    pub queued: u64,
+    pub sent: u64,
+    /// Packets dropped because the queue was full
+    pub dropped: u64,
+    /// Packets queued beyond the maximum queue length
+    pub forced: u64,
+    /// Packets discarded when the queue was flushed
+    pub flushed: u64,
+}
+
 pub fn pack_message(
     data: &[u8],
     background: Option<u8>,
@@ -52,8 +121,8 @@ pub fn pack_message(
     length: Option<usize>,
 ) -> Vec<u8> {
     let mut buf: Vec<u8> = data.into();
-    buf.resize(length.unwrap_or(313), background.unwrap_or(0));
-    if buf.len() > length.unwrap_or(313) {
+    buf.resize(length.unwrap_or(MCU_MESSAGE_LEN), background.unwrap_or(0));
+    if buf.len() > length.unwrap_or(MCU_MESSAGE_LEN) {
         warn!("MCU: too long message packed");
     }
     let buf_len_without_checksum: usize = buf.len() - 1;
@@ -65,14 +134,25 @@ pub struct MicroControllerUnit {
     pub power_state: MCUPowerState,
     pub nfc_state: NFCState,
     pub nfc_counter: i32,
-    last_poll_uid: Option<[u8; 6]>,
+    last_poll_uid: Option<[u8; 7]>,
     pending_active_remove: u32,
     pub remove_nfc_after_write: bool,
+    /// Number of backups kept for each tag, a backup is made before every write. 0 disables backups
+    pub nfc_backup_generations: usize,
+    /// Assign a fresh random UID every time a tag is newly detected
+    pub randomize_uid_on_scan: bool,
+    amiibo_keys: Option<AmiiboKeys>,
+    pub ir_camera: IrCamera,
+    /// Version reported to the console, set it to the version the console expects to avoid
+    /// update prompts
+    pub firmware_version: FirmwareVersion,
+    firmware_update: Option<FirmwareUpdate>,
     controller: ControllerState,
     pub seq_no: u32,
     pub ack_seq_no: u32,
-    pub received_data: Vec<u8>,  // FIXME: this is probably some other type
-    pub response_queue: Vec<u8>, // FIXME: this is probably some other type
+    pub received_data: Vec<u8>, // FIXME: this is probably some other type
+    response_queue: VecDeque<MCUPacket>,
+    response_queue_stats: ResponseQueueStats,
 }
 
 impl MicroControllerUnit {
@@ -85,59 +165,278 @@ impl MicroControllerUnit {
             last_poll_uid: None,
             pending_active_remove: 0,
             remove_nfc_after_write: true,
+            nfc_backup_generations: 5,
+            randomize_uid_on_scan: false,
+            amiibo_keys: None,
+            ir_camera: IrCamera::default(),
+            firmware_version: FirmwareVersion::default(),
+            firmware_update: None,
             seq_no: 0,
             ack_seq_no: 0,
             received_data: vec![],
-            response_queue: vec![],
+            response_queue: VecDeque::with_capacity(MAX_RESPONSE_QUEUE_LEN),
+            response_queue_stats: ResponseQueueStats::default(),
         }
     }
 
     #[inline]
     fn flush_response_queue(&mut self) {
-        self.response_queue = vec![]
+        self.response_queue_stats.flushed += self.response_queue.len() as u64;
+        self.response_queue.clear()
     }
 
-    fn queue_response(&mut self, resp: &[u8]) {
+    fn queue_response(&mut self, resp: MCUPacket) {
         if self.response_queue.len() < MAX_RESPONSE_QUEUE_LEN {
-            self.response_queue.extend_from_slice(resp)
+            self.response_queue_stats.queued += 1;
+            self.response_queue.push_back(resp)
         } else {
-            warn!("Full queue, dropped outgoing MCU packet")
+            self.response_queue_stats.dropped += 1;
+            warn!("Full queue, dropped outgoing MCU {:?} packet", resp.kind)
         }
     }
 
-    fn force_queue_response(&mut self, resp: &[u8]) {
-        self.response_queue.extend_from_slice(resp);
+    fn force_queue_response(&mut self, resp: MCUPacket) {
+        self.response_queue_stats.queued += 1;
+        self.response_queue.push_back(resp);
         if self.response_queue.len() > MAX_RESPONSE_QUEUE_LEN {
+            self.response_queue_stats.forced += 1;
             warn!("Forced response queue")
         }
     }
 
+    /// Number of packets waiting to be sent
+    #[inline]
+    pub fn response_queue_len(&self) -> usize {
+        self.response_queue.len()
+    }
+
+    #[inline]
+    pub fn get_response_queue_stats(&self) -> ResponseQueueStats {
+        self.response_queue_stats
+    }
+
     pub fn set_remove_nfc_after_read(&mut self, value: bool) {
         // self.remove_nfc_after_write = value
     }
 
-    fn get_status_data(&self) -> Option<Vec<u8>> {
+    /// Keys used to re-sign amiibo when their UID is randomized
+    #[inline]
+    pub fn set_amiibo_keys(&mut self, keys: Option<AmiiboKeys>) {
+        self.amiibo_keys = keys
+    }
+
+    fn get_status_data(&self, length: Option<usize>) -> Option<Vec<u8>> {
         if matches!(self.power_state, MCUPowerState::Suspended) {
             warn!("MCU: status request when disabled");
-            Some(NO_RESPONSE_MESSAGE.to_vec())
+            Some(pack_message(&[0xFF], None, None, length))
         } else if GET_STATUS_VALUES.contains(&self.power_state) {
             Some(pack_message(
                 &[
-                    hex::decode("0100000008001b").unwrap().as_slice(),
+                    hex::decode("010000").unwrap().as_slice(),
+                    &self.firmware_version.as_bytes(),
                     &[self.power_state as u8],
                 ]
                 .concat(),
                 None,
                 None,
-                None,
+                length,
             ))
         } else {
             None
         }
     }
 
+    /// Handles the MCU resume/suspend subcommand (0x22)
+    pub fn set_power_state_cmd(&mut self, power_state: u8) {
+        match SET_POWER_VALUES
+            .into_iter()
+            .find(|state| *state as u8 == power_state)
+        {
+            Some(state) => {
+                self.power_state = state;
+                if matches!(state, MCUPowerState::Suspended) {
+                    self.nfc_state = NFCState::None;
+                    self.ir_camera.reset();
+                    self.firmware_update = None;
+                    self.flush_response_queue();
+                }
+            }
+            None => error!("MCU: not implemented power state {:#x}", power_state),
+        }
+    }
+
+    /// Handles the MCU config subcommand (0x21), returns the subcommand reply data
+    pub fn set_config_cmd(&mut self, args: &[u8]) -> Vec<u8> {
+        match (args[0], args[1]) {
+            // Set MCU mode
+            (0x21, 0x00) => {
+                if matches!(self.power_state, MCUPowerState::Suspended) {
+                    if args[2] != 0 {
+                        warn!("MCU: set config while suspended");
+                    }
+                } else if let Some(state) = SET_CONFIG_VALUES
+                    .into_iter()
+                    .find(|state| *state as u8 == args[2])
+                {
+                    let in_update_mode = matches!(
+                        self.power_state,
+                        MCUPowerState::ReadyUpdate | MCUPowerState::ConfiguredUpdate
+                    );
+                    if matches!(state, MCUPowerState::ConfiguredUpdate) && !in_update_mode {
+                        warn!("MCU: update configuration requested outside of update mode");
+                    } else {
+                        self.power_state = state;
+                        self.ir_camera.reset();
+                        self.firmware_update = None;
+                    }
+                } else {
+                    error!("MCU: not implemented configuration {:#x}", args[2]);
+                }
+                self.get_status_data(Some(34))
+                    .unwrap_or_else(|| pack_message(&[0xFF], None, None, Some(34)))
+            }
+            // Set IR mode
+            (0x23, 0x01) if matches!(self.power_state, MCUPowerState::ConfiguredIR) => {
+                self.ir_camera.set_mode(&args[2..]);
+                pack_message(&[0x0B], None, None, Some(34))
+            }
+            // Write IR registers
+            (0x23, 0x04) if matches!(self.power_state, MCUPowerState::ConfiguredIR) => {
+                self.ir_camera.write_registers(&args[2..]);
+                pack_message(&[0x13, 0x00, 0x07], None, None, Some(34))
+            }
+            _ => {
+                error!(
+                    "MCU: not implemented config command {:#x} {:#x}",
+                    args[0], args[1]
+                );
+                pack_message(&[0xFF], None, None, Some(34))
+            }
+        }
+    }
+
+    /// Handles MCU requests sent with output report 0x11
+    pub fn received_11(&mut self, subcommand: u8, args: &[u8]) {
+        match subcommand {
+            // Status request
+            0x01 => {
+                if let Some(status) = self.get_status_data(None) {
+                    self.queue_response(MCUPacket::new(MCUPacketKind::Status, status))
+                }
+            }
+            // IR fragment acknowledgement
+            0x03 if matches!(self.power_state, MCUPowerState::ConfiguredIR) => {
+                self.ir_camera.process_ack(args)
+            }
+            // Firmware update data, a placeholder protocol (see `firmware_update`)
+            0x06 if matches!(self.power_state, MCUPowerState::ConfiguredUpdate) => {
+                let response = self.process_firmware_update(args);
+                self.force_queue_response(MCUPacket::new(MCUPacketKind::FirmwareUpdate, response))
+            }
+            _ => warn!("MCU: not implemented request {:#x}", subcommand),
+        }
+    }
+
+    /// Handles a firmware update request, see `UpdateOperation` for the argument layout.
+    /// The reply carries the result and the number of bytes received so far. Both layouts are
+    /// placeholders, the real update protocol is unknown.
+    fn process_firmware_update(&mut self, args: &[u8]) -> Vec<u8> {
+        let Some((&operation, args)) = args.split_first() else {
+            warn!("MCU: empty firmware update request");
+            return self.firmware_update_reply(UpdateResult::Malformed);
+        };
+        let result = match (
+            UpdateOperation::try_from(operation),
+            &mut self.firmware_update,
+        ) {
+            (Ok(UpdateOperation::Begin), _) => match FirmwareUpdate::begin(args) {
+                Ok(update) => {
+                    self.firmware_update = Some(update);
+                    UpdateResult::Ok
+                }
+                Err(result) => result,
+            },
+            (Ok(UpdateOperation::Chunk), Some(update)) => update.write_chunk(args),
+            (Ok(UpdateOperation::Finish), Some(update)) => {
+                let result = update.finish(args);
+                if matches!(result, UpdateResult::Ok) {
+                    self.firmware_version = update.get_version();
+                    self.firmware_update = None;
+                    self.power_state = MCUPowerState::ReadyUpdate;
+                }
+                result
+            }
+            (Ok(_), None) => UpdateResult::NotStarted,
+            (Err(operation), _) => {
+                error!("MCU: unknown firmware update operation {:#x}", operation);
+                UpdateResult::NotStarted
+            }
+        };
+        self.firmware_update_reply(result)
+    }
+
+    fn firmware_update_reply(&self, result: UpdateResult) -> Vec<u8> {
+        let progress = self
+            .firmware_update
+            .as_ref()
+            .map_or(0, |update| update.progress() as u32);
+        pack_message(
+            &[
+                &[FIRMWARE_UPDATE_REPORT_ID, result as u8],
+                progress.to_le_bytes().as_slice(),
+            ]
+            .concat(),
+            None,
+            None,
+            None,
+        )
+    }
+
+    /// MCU data for the next 0x31 input report. Queued responses are sent first, one per report
+    /// and in order.
+    pub fn get_data(&mut self) -> Vec<u8> {
+        if let Some(source) = self.controller.take_ir_source() {
+            self.ir_camera.source = source;
+        }
+        if let Some(packet) = self.response_queue.pop_front() {
+            self.response_queue_stats.sent += 1;
+            packet.into_bytes()
+        } else if matches!(self.power_state, MCUPowerState::ConfiguredIR) {
+            self.ir_camera.get_data()
+        } else {
+            NO_RESPONSE_MESSAGE.to_vec()
+        }
+    }
+
     fn get_nfc_status_data(&mut self) -> Vec<u8> {
         self.nfc_counter -= 1;
+
+        if self.controller.take_nfc_remove_request() {
+            self.pending_active_remove = ACTIVE_REMOVE_POLLS;
+        }
+
+        if self.randomize_uid_on_scan
+            && matches!(self.nfc_state, NFCState::Poll)
+            && self.pending_active_remove == 0
+        {
+            if let Some(nfc_tag) = self.controller.get_nfc_mut() {
+                // A different UID than on the last poll means the tag is being scanned anew. The
+                // loaded tag is replaced by a copy without source, so writes never reach the dump.
+                if Some(nfc_tag.get_uid()) != self.last_poll_uid {
+                    match nfc_tag.clone_with_random_uid(self.amiibo_keys.as_ref()) {
+                        Ok(clone) => {
+                            *nfc_tag = clone;
+                            info!(
+                                "MCU: randomized tag UID to {}",
+                                hex::encode(nfc_tag.get_uid())
+                            )
+                        }
+                        Err(why) => warn!("MCU: failed to randomize tag UID: {}", why),
+                    }
+                }
+            }
+        }
+
         let mut nfc_tag = self.controller.get_nfc();
 
         if [NFCState::Poll, NFCState::PollAgain].contains(&self.nfc_state)
@@ -180,7 +479,13 @@ impl MicroControllerUnit {
                         &[self.seq_no as u8],
                         hex::decode("0931").unwrap().as_slice(),
                         &[self.nfc_state as u8],
-                        hex::decode("0000000101020007").unwrap().as_slice(),
+                        hex::decode("00000001").unwrap().as_slice(),
+                        &[
+                            nfc_tag.get_tag_type().nfc_protocol(),
+                            nfc_tag.get_tag_type().nfc_type(),
+                            0x00,
+                            nfc_tag.get_tag_type().uid_len(),
+                        ],
                         &nfc_tag.get_uid(),
                     ]
                     .concat(),
@@ -216,6 +521,7 @@ impl MicroControllerUnit {
 
     pub fn process_nfc_write(&mut self, command: &[u8]) {
         info!("MCU: Processing NFC write");
+        let backup_generations = self.nfc_backup_generations;
         let nfc_tag = self.controller.get_nfc_mut();
         if let Some(nfc_tag) = nfc_tag {
             if command[1] == 0x07 {
@@ -226,7 +532,14 @@ impl MicroControllerUnit {
                         Bytes::copy_from_slice(&command[2..9])
                     )
                 }
-                // nfc_tag.create_backup();
+                let backed_up = backup_generations == 0
+                    || match nfc_tag.create_backup(backup_generations) {
+                        Ok(_) => true,
+                        Err(why) => {
+                            error!("Error during amiibo backup, not saving the write: {}", why);
+                            false
+                        }
+                    };
                 nfc_tag.data[16..20].copy_from_slice(&command[13..17]);
                 let mut i = 22;
                 while i + 1 < command.len() {
@@ -239,8 +552,10 @@ impl MicroControllerUnit {
                     nfc_tag.write(addr, data);
                     i += 2 + leng as usize;
                 }
-                if let Err(why) = nfc_tag.save() {
-                    warn!("Error during saving amiibo: {}", why);
+                if backed_up {
+                    if let Err(why) = nfc_tag.save() {
+                        warn!("Error during saving amiibo: {}", why);
+                    }
                 }
             } else {
                 error!("UID length is {} (not 7), aborting", command[1]);
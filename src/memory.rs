@@ -2,6 +2,9 @@ use std::{fmt::Display, ops::Deref};
 
 use thiserror::Error;
 
+/// Marks the user stick calibration as present
+const USER_CALIBRATION_MAGIC: [u8; 2] = [0xB2, 0xA1];
+
 #[derive(Debug, Clone)]
 pub struct FlashMemory {
     pub data: Vec<u8>,
@@ -45,7 +48,7 @@ impl FlashMemory {
     }
 
     pub fn get_user_l_stick_calibration(&self) -> Option<&[u8]> {
-        if self.data[0x8010..=0x8011] == [0xB2, 0xA1] {
+        if self.data[0x8010..=0x8011] == USER_CALIBRATION_MAGIC {
             Some(&self.data[0x8012..0x801B])
         } else {
             None
@@ -53,12 +56,50 @@ impl FlashMemory {
     }
 
     pub fn get_user_r_stick_calibration(&self) -> Option<&[u8]> {
-        if self.data[0x801B..=0x801C] == [0xB2, 0xA1] {
+        if self.data[0x801B..=0x801C] == USER_CALIBRATION_MAGIC {
             Some(&self.data[0x801D..0x8026])
         } else {
             None
         }
     }
+
+    /// See `StickCalibration::l_to_bytes`
+    pub fn set_user_l_stick_calibration(&mut self, calibration: &[u8; 9]) {
+        self.data[0x8010..0x8012].copy_from_slice(&USER_CALIBRATION_MAGIC);
+        self.data[0x8012..0x801B].copy_from_slice(calibration);
+    }
+
+    /// See `StickCalibration::r_to_bytes`
+    pub fn set_user_r_stick_calibration(&mut self, calibration: &[u8; 9]) {
+        self.data[0x801B..0x801D].copy_from_slice(&USER_CALIBRATION_MAGIC);
+        self.data[0x801D..0x8026].copy_from_slice(calibration);
+    }
+
+    /// Erases the user calibration, the factory calibration is used again afterwards
+    pub fn remove_user_l_stick_calibration(&mut self) {
+        self.data[0x8010..0x801B].fill(0xFF);
+    }
+
+    pub fn remove_user_r_stick_calibration(&mut self) {
+        self.data[0x801B..0x8026].fill(0xFF);
+    }
+
+    pub fn get_l_stick_device_parameters(&self) -> &[u8] {
+        &self.data[0x6086..0x6098]
+    }
+
+    pub fn get_r_stick_device_parameters(&self) -> &[u8] {
+        &self.data[0x6098..0x60AA]
+    }
+
+    /// See `StickDeviceParameters::to_bytes`
+    pub fn set_l_stick_device_parameters(&mut self, parameters: &[u8; 18]) {
+        self.data[0x6086..0x6098].copy_from_slice(parameters);
+    }
+
+    pub fn set_r_stick_device_parameters(&mut self, parameters: &[u8; 18]) {
+        self.data[0x6098..0x60AA].copy_from_slice(parameters);
+    }
 }
 
 impl Deref for FlashMemory {
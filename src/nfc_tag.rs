@@ -1,12 +1,22 @@
 use bytes::Bytes;
+use chrono::Local;
 use log::{error, info, warn};
+use rand::random;
 use std::{
     error::Error,
-    fs,
-    io::{self, Write},
+    ffi::OsString,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
 };
+use thiserror::Error;
 use tokio::{fs::File, io::AsyncReadExt};
 
+use crate::amiibo::{AmiiboCryptoError, AmiiboKeys};
+
+/// Backups of a tag are kept in `<source dir>/.backups/<file name>/<timestamp>/`
+const BACKUP_DIR_NAME: &str = ".backups";
+
 // TODO: other method impls
 #[derive(Debug, Clone)]
 pub struct NFCTag {
@@ -18,12 +28,15 @@ pub struct NFCTag {
 impl NFCTag {
     pub fn new(data: &[u8], tag_type: Option<NFCTagType>, source: Option<String>) -> Self {
         let tag_type = tag_type.unwrap_or(NFCTagType::Amiibo);
-        if matches!(tag_type, NFCTagType::Amiibo) {
-            if data.len() == 572 {
-                info!("Long amiibo loaded, manufacturer signature is ignored")
-            } else if data.len() != 540 {
-                warn!("Illegal Amiibo tag size")
+        if tag_type.is_valid_size(data.len()) {
+            if data.len() != tag_type.size() {
+                info!(
+                    "Long {} loaded, manufacturer signature is ignored",
+                    tag_type
+                )
             }
+        } else {
+            warn!("Illegal {} tag size {}", tag_type, data.len())
         }
         Self {
             data: data.into(),
@@ -35,7 +48,7 @@ impl NFCTag {
     pub async fn load_amiibo(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let mut reader = File::open(source).await?;
         let mut buf = vec![];
-        reader.read_to_end(&mut buf).await;
+        reader.read_to_end(&mut buf).await?;
         Ok(Self::new(
             &buf,
             Some(NFCTagType::Amiibo),
@@ -43,13 +56,93 @@ impl NFCTag {
         ))
     }
 
-    pub fn get_uid(&self) -> [u8; 6] {
+    /// Loads a tag of any supported type, the type is detected from the file contents
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let buf = tokio::fs::read(source).await?;
+        let tag_type = NFCTagType::detect(&buf).ok_or(UnknownTagType(buf.len()))?;
+        info!("Detected {} in {}", tag_type, source);
+        Ok(Self::new(&buf, Some(tag_type), Some(source.into())))
+    }
+
+    #[inline]
+    pub fn get_tag_type(&self) -> NFCTagType {
+        self.tag_type
+    }
+
+    pub fn get_uid(&self) -> [u8; 7] {
         [&self.data[0..3], &self.data[4..8]]
             .concat()
             .try_into()
             .unwrap()
     }
 
+    /// Sets the UID together with both check bytes and, for amiibo, the NTAG password derived from
+    /// it. If keys are given, amiibo are re-signed so that the console accepts the new UID,
+    /// otherwise the signature no longer matches.
+    pub fn set_uid(
+        &mut self,
+        uid: [u8; 7],
+        keys: Option<&AmiiboKeys>,
+    ) -> Result<(), AmiiboCryptoError> {
+        let is_amiibo = matches!(self.tag_type, NFCTagType::Amiibo);
+        match keys {
+            Some(keys) if is_amiibo => {
+                let mut plain = keys.decrypt(&self.data)?;
+                Self::write_uid(&mut plain, uid, is_amiibo);
+                self.data = keys.encrypt(&plain)?;
+            }
+            _ => {
+                if is_amiibo {
+                    warn!("No amiibo keys available, tag signature won't match the new UID");
+                }
+                Self::write_uid(&mut self.data, uid, is_amiibo);
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns a fresh random UID, see `set_uid`. The first byte is kept as the NXP manufacturer
+    /// code, like on real tags.
+    pub fn randomize_uid(
+        &mut self,
+        keys: Option<&AmiiboKeys>,
+    ) -> Result<[u8; 7], AmiiboCryptoError> {
+        let mut uid: [u8; 7] = random();
+        uid[0] = 0x04;
+        self.set_uid(uid, keys)?;
+        Ok(uid)
+    }
+
+    /// Creates a copy of the tag with a random UID. The copy has no source, so it is never saved
+    /// over the original file.
+    pub fn clone_with_random_uid(
+        &self,
+        keys: Option<&AmiiboKeys>,
+    ) -> Result<Self, AmiiboCryptoError> {
+        let mut clone = Self {
+            data: self.data.clone(),
+            tag_type: self.tag_type,
+            source: None,
+        };
+        clone.randomize_uid(keys)?;
+        Ok(clone)
+    }
+
+    fn write_uid(data: &mut [u8], uid: [u8; 7], with_password: bool) {
+        data[0..3].copy_from_slice(&uid[0..3]);
+        data[3] = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+        data[4..8].copy_from_slice(&uid[3..7]);
+        data[8] = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+        if with_password && data.len() >= 0x218 {
+            data[0x214..0x218].copy_from_slice(&[
+                0xAA ^ uid[1] ^ uid[3],
+                0x55 ^ uid[2] ^ uid[4],
+                0xAA ^ uid[3] ^ uid[5],
+                0x55 ^ uid[4] ^ uid[6],
+            ]);
+        }
+    }
+
     pub fn write(&mut self, idx: usize, data: &[u8]) {
         if idx > self.data.len() || idx + data.len() > self.data.len() {
             error!(
@@ -62,19 +155,310 @@ impl NFCTag {
         self.data[idx..(idx + data.len())].copy_from_slice(data);
     }
 
+    /// Writes the tag back to its source. The data is written to a temporary file first, so an
+    /// interrupted save can't leave a half-written tag behind.
     pub fn save(&mut self) -> Result<(), io::Error> {
         if let Some(source) = &self.source {
-            let mut writer = fs::File::open(source)?;
-            writer.write_all(&self.data)?;
+            let tmp_path = format!("{}.tmp", source);
+            fs::write(&tmp_path, &self.data)?;
+            fs::rename(&tmp_path, source)?;
             info!("Saved altered amiibo as {}", source);
         } else {
             warn!("No save path provided, ignoring save call");
         }
         Ok(())
     }
+
+    /// Directory holding the backup generations and the file name used inside each of them
+    fn backup_location(&self) -> Option<(PathBuf, OsString)> {
+        let source = Path::new(self.source.as_ref()?);
+        let file_name = source.file_name()?;
+        Some((
+            source.with_file_name(BACKUP_DIR_NAME).join(file_name),
+            file_name.to_owned(),
+        ))
+    }
+
+    /// Snapshots the current tag contents into a new timestamped backup directory and removes
+    /// all but the `generations` newest backups. Returns the path of the created backup.
+    pub fn create_backup(&self, generations: usize) -> Result<Option<PathBuf>, io::Error> {
+        let (root, file_name) = match self.backup_location() {
+            Some(location) => location,
+            None => {
+                warn!("No source path provided, ignoring backup call");
+                return Ok(None);
+            }
+        };
+
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S%.3f").to_string();
+        let mut dir = root.join(&timestamp);
+        let mut i = 1;
+        while dir.exists() {
+            dir = root.join(format!("{}.{}", timestamp, i));
+            i += 1;
+        }
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(file_name);
+        fs::write(&path, &self.data)?;
+        info!("Created backup {}", path.display());
+
+        for old_backup in self.list_backups()?.iter().skip(generations) {
+            if let Some(old_dir) = old_backup.parent() {
+                fs::remove_dir_all(old_dir)?;
+                info!("Removed old backup {}", old_dir.display());
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// Paths of the existing backups of this tag, newest first
+    pub fn list_backups(&self) -> Result<Vec<PathBuf>, io::Error> {
+        let (root, file_name) = match self.backup_location() {
+            Some(location) => location,
+            None => return Ok(vec![]),
+        };
+        if !root.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut backups = fs::read_dir(root)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().join(&file_name))
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        backups.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(backups)
+    }
+
+    /// Replaces the tag contents with the given backup generation (0 being the newest) and saves
+    /// it over the source.
+    pub fn restore_backup(&mut self, generation: usize) -> Result<(), io::Error> {
+        let backups = self.list_backups()?;
+        let backup = backups.get(generation).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No backup generation {}, {} available",
+                    generation,
+                    backups.len()
+                ),
+            )
+        })?;
+        self.data = fs::read(backup)?;
+        info!("Restored backup {}", backup.display());
+        self.save()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum NFCTagType {
+    /// NTAG215 holding amiibo data
     Amiibo,
+    Ntag213,
+    Ntag215,
+    Ntag216,
+    MifareUltralight,
+}
+
+impl NFCTagType {
+    /// Size of a full dump (all pages) without the manufacturer signature
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Amiibo | Self::Ntag215 => 135 * 4,
+            Self::Ntag213 => 45 * 4,
+            Self::Ntag216 => 231 * 4,
+            Self::MifareUltralight => 16 * 4,
+        }
+    }
+
+    /// NTAG dumps may additionally contain the 32 byte manufacturer signature
+    pub fn is_valid_size(&self, len: usize) -> bool {
+        len == self.size() || (!matches!(self, Self::MifareUltralight) && len == self.size() + 32)
+    }
+
+    /// Guesses the tag type from the dump size. NTAG215 dumps with the amiibo magic byte in page 4
+    /// are detected as amiibo.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        [
+            Self::Amiibo,
+            Self::Ntag213,
+            Self::Ntag215,
+            Self::Ntag216,
+            Self::MifareUltralight,
+        ]
+        .into_iter()
+        .filter(|tag_type| tag_type.is_valid_size(data.len()))
+        .find(|tag_type| !matches!(tag_type, Self::Amiibo) || data[0x10] == 0xA5)
+    }
+
+    /// NFC protocol as reported by the MCU, 0x01 being ISO 14443 type A
+    #[inline]
+    pub fn nfc_protocol(&self) -> u8 {
+        match self {
+            Self::Amiibo
+            | Self::Ntag213
+            | Self::Ntag215
+            | Self::Ntag216
+            | Self::MifareUltralight => 0x01,
+        }
+    }
+
+    /// NFC Forum tag type as reported by the MCU
+    #[inline]
+    pub fn nfc_type(&self) -> u8 {
+        match self {
+            Self::Amiibo
+            | Self::Ntag213
+            | Self::Ntag215
+            | Self::Ntag216
+            | Self::MifareUltralight => 0x02,
+        }
+    }
+
+    /// NTAG and MIFARE Ultralight tags have double size UIDs
+    #[inline]
+    pub fn uid_len(&self) -> u8 {
+        match self {
+            Self::Amiibo
+            | Self::Ntag213
+            | Self::Ntag215
+            | Self::Ntag216
+            | Self::MifareUltralight => 7,
+        }
+    }
+}
+
+impl Display for NFCTagType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Amiibo => "amiibo",
+                Self::Ntag213 => "NTAG213",
+                Self::Ntag215 => "NTAG215",
+                Self::Ntag216 => "NTAG216",
+                Self::MifareUltralight => "MIFARE Ultralight",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error("No supported NFC tag type has a size of {0} bytes")]
+pub struct UnknownTagType(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amiibo::AMIIBO_SIZE;
+
+    /// NTAG213 saved in a new directory under the system's temp directory
+    fn saved_tag(name: &str) -> (NFCTag, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("nfc_tag_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tag.bin");
+        let data = vec![0; NFCTagType::Ntag213.size()];
+        fs::write(&path, &data).unwrap();
+        let tag = NFCTag::new(
+            &data,
+            Some(NFCTagType::Ntag213),
+            Some(path.to_str().unwrap().into()),
+        );
+        (tag, dir)
+    }
+
+    #[test]
+    fn backups_are_pruned_and_listed_newest_first() {
+        let (mut tag, dir) = saved_tag("backups");
+        for generation in 1..=4 {
+            tag.data[0x20] = generation;
+            tag.create_backup(3).unwrap().unwrap();
+        }
+        let backups = tag.list_backups().unwrap();
+        let contents = backups
+            .iter()
+            .map(|backup| fs::read(backup).unwrap()[0x20])
+            .collect::<Vec<_>>();
+        assert_eq!(contents, [4, 3, 2]);
+        assert!(backups
+            .iter()
+            .all(|backup| backup.starts_with(dir.join(BACKUP_DIR_NAME).join("tag.bin"))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restoring_replaces_the_tag_and_its_file() {
+        let (mut tag, dir) = saved_tag("restore");
+        tag.data[0x20] = 1;
+        tag.create_backup(5).unwrap();
+        tag.data[0x20] = 2;
+        tag.create_backup(5).unwrap();
+
+        tag.data[0x20] = 3;
+        tag.restore_backup(1).unwrap();
+        assert_eq!(tag.data[0x20], 1);
+        assert_eq!(fs::read(dir.join("tag.bin")).unwrap()[0x20], 1);
+        let error = tag.restore_backup(2).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tags_without_source_have_no_backups() {
+        let tag = NFCTag::new(&[0; 540], None, None);
+        assert_eq!(tag.create_backup(5).unwrap(), None);
+        assert!(tag.list_backups().unwrap().is_empty());
+    }
+
+    #[test]
+    fn uid_is_written_with_check_bytes_and_password() {
+        let uid = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let mut tag = NFCTag::new(&[0; AMIIBO_SIZE], Some(NFCTagType::Amiibo), None);
+        tag.set_uid(uid, None).unwrap();
+        assert_eq!(tag.get_uid(), uid);
+        // BCC0 is 0x88 ^ uid0 ^ uid1 ^ uid2, BCC1 the XOR of the other four bytes
+        assert_eq!(
+            tag.data[0..9],
+            [0x04, 0x11, 0x22, 0xBF, 0x33, 0x44, 0x55, 0x66, 0x44]
+        );
+        assert_eq!(tag.data[0x214..0x218], [0x88, 0x33, 0xCC, 0x77]);
+
+        // Other tags have no password derived from the UID
+        let mut tag = NFCTag::new(&[0; AMIIBO_SIZE], Some(NFCTagType::Ntag215), None);
+        tag.set_uid(uid, None).unwrap();
+        assert_eq!(tag.data[0x214..0x218], [0; 4]);
+    }
+
+    #[test]
+    fn tag_types_are_detected_from_the_size() {
+        for (len, tag_type) in [
+            (180, NFCTagType::Ntag213),
+            (540, NFCTagType::Ntag215),
+            (924, NFCTagType::Ntag216),
+            (64, NFCTagType::MifareUltralight),
+        ] {
+            let mut data = vec![0; len];
+            assert_eq!(NFCTagType::detect(&data), Some(tag_type));
+            assert!(tag_type.is_valid_size(len));
+            // NTAG dumps may have the manufacturer signature appended
+            data.extend([0; 32]);
+            let signed = (tag_type != NFCTagType::MifareUltralight).then_some(tag_type);
+            assert_eq!(NFCTagType::detect(&data), signed);
+            assert_eq!(tag_type.is_valid_size(len + 32), signed.is_some());
+        }
+        assert_eq!(NFCTagType::detect(&[0; 100]), None);
+        assert!(!NFCTagType::Ntag215.is_valid_size(541));
+    }
+
+    #[test]
+    fn amiibo_are_told_apart_by_their_magic_byte() {
+        let mut data = vec![0; AMIIBO_SIZE];
+        data[0x10] = 0xA5;
+        assert_eq!(NFCTagType::detect(&data), Some(NFCTagType::Amiibo));
+        data.extend([0; 32]);
+        assert_eq!(NFCTagType::detect(&data), Some(NFCTagType::Amiibo));
+        data[0x10] = 0x00;
+        assert_eq!(NFCTagType::detect(&data), Some(NFCTagType::Ntag215));
+    }
 }
@@ -1,11 +1,23 @@
 use crate::{
     controller::Controller,
-    controller_state::ControllerState,
+    controller_state::{ControllerEvent, ControllerState},
     memory::{FlashMemory, SizeMismatch},
 };
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
+use log::debug;
 use std::iter::FromIterator;
+use tokio::sync::broadcast;
+
+/// HID header of output reports sent over Bluetooth
+const OUTPUT_REPORT_HEADER: u8 = 0xA2;
+/// Output report with rumble data and a subcommand
+const RUMBLE_AND_SUBCOMMAND: u8 = 0x01;
+/// Output report with rumble data only
+const RUMBLE_ONLY: u8 = 0x10;
+const SET_PLAYER_LIGHTS: u8 = 0x30;
+/// Rumble data the Switch sends while the motors are idle
+const NEUTRAL_RUMBLE: [u8; 8] = [0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40];
 
 lazy_static! {
     pub static ref DELAY_MAP: HashMap<u8, f32> = HashMap::from_iter(vec![
@@ -23,14 +35,68 @@ pub enum SwitchState {
     AwaitingMaxSlots,
 }
 
+/// Turns the output reports of the Switch into controller events. Rumble is only reported when it
+/// changes, so idle rumble data in every report doesn't flood the subscribers.
+#[derive(Debug, Clone)]
+pub struct OutputReportHandler {
+    events: broadcast::Sender<ControllerEvent>,
+    rumble: [u8; 8],
+}
+
+impl OutputReportHandler {
+    pub fn new(controller_state: &ControllerState) -> Self {
+        Self {
+            events: controller_state.get_event_sender(),
+            rumble: NEUTRAL_RUMBLE,
+        }
+    }
+
+    /// `report` starts with the HID header. Subcommands other than setting the player lights are
+    /// ignored.
+    pub fn report_received(&mut self, report: &[u8]) {
+        let (Some(&OUTPUT_REPORT_HEADER), Some(&report_id)) = (report.first(), report.get(1))
+        else {
+            debug!("Ignoring output report without header");
+            return;
+        };
+        if !matches!(report_id, RUMBLE_AND_SUBCOMMAND | RUMBLE_ONLY) {
+            return;
+        }
+        if let Some(rumble) = report.get(3..11) {
+            let rumble: [u8; 8] = rumble.try_into().unwrap();
+            if rumble != self.rumble {
+                self.rumble = rumble;
+                self.emit(ControllerEvent::Rumble(rumble));
+            }
+        }
+        if report_id == RUMBLE_AND_SUBCOMMAND && report.get(11) == Some(&SET_PLAYER_LIGHTS) {
+            if let Some(&lights) = report.get(12) {
+                self.emit(ControllerEvent::PlayerLights(lights));
+            }
+        }
+    }
+
+    #[inline]
+    fn emit(&self, event: ControllerEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
+
 pub struct ControllerProtocol {
     controller: Controller,
     controller_state: ControllerState,
     spi_flash: Option<FlashMemory>,
     is_pairing: bool,
+    output_reports: OutputReportHandler,
 }
 
 impl ControllerProtocol {
+    /// Handles a report the Switch sent to the controller
+    pub fn report_received(&mut self, report: &[u8]) {
+        self.output_reports.report_received(report)
+    }
+
     pub async fn send_controller_state(&mut self) {
         todo!()
     }
@@ -40,11 +106,13 @@ impl ControllerProtocol {
         spi_flash: Option<FlashMemory>,
         reconnect: Option<bool>,
     ) -> Result<Self, SizeMismatch> {
+        let controller_state = ControllerState::new(controller, spi_flash.clone());
         Ok(Self {
             controller,
-            spi_flash: spi_flash.clone(),
+            spi_flash,
             is_pairing: !reconnect.unwrap_or(false),
-            controller_state: ControllerState::new(controller, spi_flash),
+            output_reports: OutputReportHandler::new(&controller_state),
+            controller_state,
         })
         // TODO
     }
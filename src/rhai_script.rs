@@ -0,0 +1,513 @@
+//! Rhai scripts with access to the controller. A script runs on a blocking thread of the runtime
+//! and asks the CLI for every input, so it sees the same state as commands typed at the same time.
+//! Functions available to scripts:
+//! - `press(buttons)`, `release(buttons)`, `push(buttons)` and `push(buttons, seconds)`, buttons
+//!   being an array or a string of names separated by spaces
+//! - `stick(side, direction)`, `stick(side, direction, value)` and
+//!   `stick(side, direction, value, value2)`, same as the `stick` command
+//! - `nfc_load(name)` and `nfc_remove()`
+//! - `command(line)` to run any CLI command, returning its output
+//! - `wait(milliseconds)` and `wait_frames(reports)`
+//! - `on_rumble(|data| ...)` and `on_player_lights(|lights| ...)`, called while the script waits.
+//!   With callbacks registered, the script keeps waiting for events after its last line until it
+//!   is cancelled.
+//!
+//! Scripts can't import modules or use `eval`, and are limited in call depth and data sizes.
+
+use std::{
+    cell::RefCell,
+    error::Error,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rhai::{
+    module_resolvers::DummyModuleResolver, Array, Dynamic, Engine, EvalAltResult, FnPtr,
+    NativeCallContext, Position, AST,
+};
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, oneshot},
+};
+
+use crate::{
+    cli::CliError,
+    controller_state::{ControllerEvent, ControllerState, ReportCounter},
+};
+
+/// How often a waiting script checks whether it was cancelled
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_ARRAY_SIZE: usize = 1 << 16;
+
+/// What a script asks the CLI to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptRequest {
+    Press(Vec<String>),
+    Release(Vec<String>),
+    Push(Vec<String>, Option<f32>),
+    /// A CLI command and its arguments
+    Command(String, Vec<String>),
+}
+
+pub enum ScriptMessage {
+    /// The reply is sent back to the script, which waits for it
+    Request(ScriptRequest, oneshot::Sender<Result<String, CliError>>),
+    Finished(Result<(), RhaiScriptError>),
+}
+
+/// A script running on a blocking thread. The owner serves its requests with `next_message` until
+/// it is finished.
+pub struct RhaiScript {
+    name: String,
+    messages: tokio::sync::mpsc::Receiver<ScriptMessage>,
+    events: broadcast::Receiver<ControllerEvent>,
+    forwarded_events: mpsc::Sender<ControllerEvent>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RhaiScript {
+    pub fn spawn(name: &str, source: String, controller_state: &ControllerState) -> Self {
+        let (message_tx, messages) = tokio::sync::mpsc::channel(1);
+        let (forwarded_events, event_rx) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let reports = controller_state.get_report_counter();
+        let runtime = Handle::current();
+        let script_cancelled = cancelled.clone();
+        // Scripts block on every request and wait. The runtime waits for them when shutting down,
+        // which is short since dropping `Self` cancels the script.
+        runtime.clone().spawn_blocking(move || {
+            let context = ScriptContext {
+                messages: message_tx.clone(),
+                events: event_rx,
+                reports,
+                runtime,
+                cancelled: script_cancelled,
+                rumble_callbacks: RefCell::new(vec![]),
+                player_lights_callbacks: RefCell::new(vec![]),
+            };
+            let result = run_script(&source, Rc::new(context));
+            let _ = message_tx.blocking_send(ScriptMessage::Finished(result));
+        });
+        Self {
+            name: name.into(),
+            messages,
+            events: controller_state.subscribe_events(),
+            forwarded_events,
+            cancelled,
+        }
+    }
+
+    pub async fn load(
+        path: &str,
+        controller_state: &ControllerState,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let source = tokio::fs::read_to_string(path).await?;
+        Ok(Self::spawn(path, source, controller_state))
+    }
+
+    #[inline]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Makes the script stop at its next operation or wait, it then sends `Finished`
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed)
+    }
+
+    /// Passes controller events on to the script while waiting. Cancel safe.
+    pub async fn next_message(&mut self) -> ScriptMessage {
+        loop {
+            tokio::select! {
+                message = self.messages.recv() => {
+                    // The thread always sends Finished, unless it panicked
+                    return message.unwrap_or(ScriptMessage::Finished(Err(RhaiScriptError::Stopped)));
+                }
+                event = self.events.recv() => match event {
+                    Ok(event) => {
+                        let _ = self.forwarded_events.send(event);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    // Only happens if the controller state is gone, the script can't do anything then
+                    Err(broadcast::error::RecvError::Closed) => {
+                        self.cancel();
+                        let message = self.messages.recv().await;
+                        return message.unwrap_or(ScriptMessage::Finished(Err(RhaiScriptError::Stopped)));
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Drop for RhaiScript {
+    fn drop(&mut self) {
+        self.cancel()
+    }
+}
+
+/// Shared by the functions registered for the script
+struct ScriptContext {
+    messages: tokio::sync::mpsc::Sender<ScriptMessage>,
+    events: mpsc::Receiver<ControllerEvent>,
+    reports: Arc<ReportCounter>,
+    /// To wait for reports
+    runtime: Handle,
+    cancelled: Arc<AtomicBool>,
+    rumble_callbacks: RefCell<Vec<FnPtr>>,
+    player_lights_callbacks: RefCell<Vec<FnPtr>>,
+}
+
+type Callback<'c> = &'c dyn Fn(&FnPtr, Dynamic) -> Result<Dynamic, Box<EvalAltResult>>;
+
+impl ScriptContext {
+    fn check_cancelled(&self) -> Result<(), Box<EvalAltResult>> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(terminated())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn request(&self, request: ScriptRequest) -> Result<String, Box<EvalAltResult>> {
+        self.check_cancelled()?;
+        let (reply_tx, reply) = oneshot::channel();
+        self.messages
+            .blocking_send(ScriptMessage::Request(request, reply_tx))
+            .map_err(|_| terminated())?;
+        reply
+            .blocking_recv()
+            .map_err(|_| terminated())?
+            .map_err(|why| why.to_string().into())
+    }
+
+    #[inline]
+    fn has_callbacks(&self) -> bool {
+        !(self.rumble_callbacks.borrow().is_empty()
+            && self.player_lights_callbacks.borrow().is_empty())
+    }
+
+    fn dispatch(&self, event: ControllerEvent, call: Callback) -> Result<(), Box<EvalAltResult>> {
+        // Cloned so callbacks can register more callbacks
+        let (callbacks, arg) = match event {
+            ControllerEvent::Rumble(data) => (
+                self.rumble_callbacks.borrow().clone(),
+                Dynamic::from_blob(data.to_vec()),
+            ),
+            ControllerEvent::PlayerLights(lights) => (
+                self.player_lights_callbacks.borrow().clone(),
+                Dynamic::from_int(lights.into()),
+            ),
+        };
+        // Return values of callbacks are not used
+        for callback in &callbacks {
+            let _ = call(callback, arg.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Handles events until `timeout` passed
+    fn wait(&self, timeout: Duration, call: Callback) -> Result<(), Box<EvalAltResult>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.check_cancelled()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            match self.events.recv_timeout(remaining.min(POLL_INTERVAL)) {
+                Ok(event) => self.dispatch(event, call)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(terminated()),
+            }
+        }
+    }
+
+    /// Handles events until `reports` more input reports were sent
+    fn wait_frames(&self, reports: u32, call: Callback) -> Result<(), Box<EvalAltResult>> {
+        let target = self.reports.get() + reports as u64;
+        loop {
+            self.check_cancelled()?;
+            loop {
+                match self.events.try_recv() {
+                    Ok(event) => self.dispatch(event, call)?,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Err(terminated()),
+                }
+            }
+            let sent = self.runtime.block_on(tokio::time::timeout(
+                POLL_INTERVAL,
+                self.reports.wait_until(target),
+            ));
+            if sent.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Stops the script, also used when the CLI side is gone
+#[inline]
+fn terminated() -> Box<EvalAltResult> {
+    EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into()
+}
+
+/// Buttons given as an array or as names separated by spaces
+fn button_list(buttons: Dynamic) -> Vec<String> {
+    if buttons.is_array() {
+        buttons
+            .cast::<Array>()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    } else {
+        buttons
+            .to_string()
+            .split_whitespace()
+            .map(Into::into)
+            .collect()
+    }
+}
+
+fn as_seconds(seconds: Dynamic) -> Result<f32, Box<EvalAltResult>> {
+    seconds
+        .as_float()
+        .or_else(|_| seconds.as_int().map(|seconds| seconds as f64))
+        .map(|seconds| seconds as f32)
+        .map_err(|_| format!("expected seconds, got {}", seconds.type_name()).into())
+}
+
+fn as_count(count: i64) -> Result<u32, Box<EvalAltResult>> {
+    count
+        .try_into()
+        .map_err(|_| format!("expected a count >= 0, got {}", count).into())
+}
+
+fn create_engine(context: &Rc<ScriptContext>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_call_levels(MAX_CALL_LEVELS);
+    engine.set_max_string_size(MAX_STRING_SIZE);
+    engine.set_max_array_size(MAX_ARRAY_SIZE);
+    engine.set_max_map_size(MAX_ARRAY_SIZE);
+    let cancelled = context.cancelled.clone();
+    engine.on_progress(move |_| cancelled.load(Ordering::Relaxed).then_some(Dynamic::UNIT));
+
+    let ctx = context.clone();
+    engine.register_fn("press", move |buttons: Dynamic| {
+        ctx.request(ScriptRequest::Press(button_list(buttons)))
+            .map(|_| ())
+    });
+    let ctx = context.clone();
+    engine.register_fn("release", move |buttons: Dynamic| {
+        ctx.request(ScriptRequest::Release(button_list(buttons)))
+            .map(|_| ())
+    });
+    let ctx = context.clone();
+    engine.register_fn("push", move |buttons: Dynamic| {
+        ctx.request(ScriptRequest::Push(button_list(buttons), None))
+            .map(|_| ())
+    });
+    let ctx = context.clone();
+    engine.register_fn("push", move |buttons: Dynamic, seconds: Dynamic| {
+        ctx.request(ScriptRequest::Push(
+            button_list(buttons),
+            Some(as_seconds(seconds)?),
+        ))
+        .map(|_| ())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("stick", move |side: &str, direction: &str| {
+        ctx.request(ScriptRequest::Command(
+            "stick".into(),
+            vec![side.into(), direction.into()],
+        ))
+    });
+    let ctx = context.clone();
+    engine.register_fn(
+        "stick",
+        move |side: &str, direction: &str, value: Dynamic| {
+            let args = vec![side.into(), direction.into(), value.to_string()];
+            ctx.request(ScriptRequest::Command("stick".into(), args))
+        },
+    );
+    let ctx = context.clone();
+    engine.register_fn(
+        "stick",
+        move |side: &str, direction: &str, value: Dynamic, value2: Dynamic| {
+            let args = vec![
+                side.into(),
+                direction.into(),
+                value.to_string(),
+                value2.to_string(),
+            ];
+            ctx.request(ScriptRequest::Command("stick".into(), args))
+        },
+    );
+
+    let ctx = context.clone();
+    engine.register_fn("nfc_load", move |name: &str| {
+        ctx.request(ScriptRequest::Command(
+            "nfc".into(),
+            vec!["load".into(), name.into()],
+        ))
+    });
+    let ctx = context.clone();
+    engine.register_fn("nfc_remove", move || {
+        ctx.request(ScriptRequest::Command("nfc".into(), vec!["remove".into()]))
+    });
+    let ctx = context.clone();
+    engine.register_fn("command", move |line: &str| {
+        let mut args = shlex::split(line)
+            .filter(|args| !args.is_empty())
+            .ok_or_else(|| CliError::InvalidQuoting.to_string())?;
+        let cmd = args.remove(0);
+        ctx.request(ScriptRequest::Command(cmd, args))
+    });
+
+    let ctx = context.clone();
+    engine.register_fn(
+        "wait",
+        move |call_context: NativeCallContext, milliseconds: i64| {
+            let timeout = Duration::from_millis(as_count(milliseconds)?.into());
+            ctx.wait(timeout, &|callback, arg| {
+                callback.call_within_context(&call_context, (arg,))
+            })
+        },
+    );
+    let ctx = context.clone();
+    engine.register_fn(
+        "wait_frames",
+        move |call_context: NativeCallContext, reports: i64| {
+            ctx.wait_frames(as_count(reports)?, &|callback, arg| {
+                callback.call_within_context(&call_context, (arg,))
+            })
+        },
+    );
+    let ctx = context.clone();
+    engine.register_fn("on_rumble", move |callback: FnPtr| {
+        ctx.rumble_callbacks.borrow_mut().push(callback)
+    });
+    let ctx = context.clone();
+    engine.register_fn("on_player_lights", move |callback: FnPtr| {
+        ctx.player_lights_callbacks.borrow_mut().push(callback)
+    });
+    engine
+}
+
+fn run_script(source: &str, context: Rc<ScriptContext>) -> Result<(), RhaiScriptError> {
+    let engine = create_engine(&context);
+    let ast: AST = engine
+        .compile(source)
+        .map_err(|why| RhaiScriptError::Compile(why.to_string()))?;
+    let call: Callback = &|callback, arg| callback.call(&engine, &ast, (arg,));
+    let result = engine.run_ast(&ast).and_then(|_| {
+        while context.has_callbacks() {
+            context.wait(POLL_INTERVAL, call)?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => Ok(()),
+        Err(_) if context.cancelled.load(Ordering::Relaxed) => Err(RhaiScriptError::Cancelled),
+        Err(why) => Err(RhaiScriptError::Runtime(why.to_string())),
+    }
+}
+
+/// Rhai errors are converted to text since they can't be sent between threads
+#[derive(Debug, Error)]
+pub enum RhaiScriptError {
+    #[error("Couldn't compile the script: {0}")]
+    Compile(String),
+    #[error("{0}")]
+    Runtime(String),
+    #[error("The script was cancelled.")]
+    Cancelled,
+    #[error("The script stopped unexpectedly.")]
+    Stopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::ControllerCli, protocol::OutputReportHandler, transport::connected_state};
+
+    #[tokio::test]
+    async fn script_inputs_are_sent() {
+        let (mut controller_state, transport) = connected_state();
+        let source = r#"press("a"); wait_frames(2); release("a");"#;
+        let script = RhaiScript::spawn("test", source.into(), &controller_state);
+        let mut cli = ControllerCli::new(&mut controller_state);
+        tokio::time::timeout(Duration::from_secs(1), cli.run_rhai_script(script))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Reports keep being sent while the script runs, so A may be in more than 3 of them
+        let pressed = transport
+            .take_reports()
+            .iter()
+            .map(|report| report[4] & 0x10 != 0)
+            .collect::<Vec<_>>();
+        let first = pressed.iter().position(|&pressed| pressed).unwrap();
+        let held = pressed[first..]
+            .iter()
+            .take_while(|&&pressed| pressed)
+            .count();
+        assert!(held >= 3);
+        assert!(pressed.len() > first + held);
+        assert!(pressed[(first + held)..].iter().all(|&pressed| !pressed));
+    }
+
+    #[tokio::test]
+    async fn output_reports_call_the_callbacks() {
+        let (mut controller_state, transport) = connected_state();
+        let mut events = controller_state.subscribe_events();
+        let mut output_reports = OutputReportHandler::new(&controller_state);
+        let source = r#"
+            on_player_lights(|lights| if lights == 0x01 { press("b") });
+            on_rumble(|data| if data[0] == 0x28 { press("x") });
+        "#;
+        let script = RhaiScript::spawn("test", source.into(), &controller_state);
+
+        // Player 1 light with idle rumble, which isn't an event
+        let mut report = [0; 49];
+        report[0..3].copy_from_slice(&[0xA2, 0x01, 0x00]);
+        report[3..11].copy_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+        report[11..13].copy_from_slice(&[0x30, 0x01]);
+        output_reports.report_received(&report);
+        let rumble = [0x28, 0x88, 0x60, 0x61, 0x28, 0x88, 0x60, 0x61];
+        let mut report = [0; 11];
+        report[0..3].copy_from_slice(&[0xA2, 0x10, 0x01]);
+        report[3..11].copy_from_slice(&rumble);
+        output_reports.report_received(&report);
+        // Unchanged rumble isn't reported again
+        output_reports.report_received(&report);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ControllerEvent::PlayerLights(0x01)
+        );
+        assert_eq!(events.try_recv().unwrap(), ControllerEvent::Rumble(rumble));
+        assert!(events.try_recv().is_err());
+
+        // With callbacks registered the script waits for events until it is cancelled
+        let mut cli = ControllerCli::new(&mut controller_state);
+        let result =
+            tokio::time::timeout(Duration::from_millis(300), cli.run_rhai_script(script)).await;
+        assert!(result.is_err());
+        // B is 0x20 and X 0x40 of the first button byte
+        let last_report = transport.take_reports().pop().unwrap();
+        assert_eq!(last_report[4], 0x60);
+    }
+}
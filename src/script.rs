@@ -0,0 +1,592 @@
+//! Runner for automation scripts (.jcs files) made of CLI commands, one per line. On top of the
+//! CLI commands and buttons, scripts know:
+//! - `# comment`
+//! - `wait <time>`: `100ms`, `2s` or `3f` for 3 input reports
+//! - `hold <buttons...>` and `release <buttons...>` to keep buttons pressed across lines, `hold`
+//!   with a duration is the CLI command
+//! - `set <name> = <expression>`, `$name` anywhere in a line is replaced by the value
+//! - `repeat <count> {` ... `}`
+//! - `if <expression> {` ... `} else {` ... `}`
+//! - `<label>:` and `goto <label>`
+//!
+//! Expressions are a single value or `<value> <operator> <value>`, with `+ - * / %` on integers
+//! and `== != < <= > >=` comparing integers, or strings for `==` and `!=`. Conditions are false
+//! for "0", "false" and empty values.
+
+use std::{error::Error, fmt::Display, time::Duration};
+
+use hashbrown::HashMap;
+use thiserror::Error;
+
+use crate::{
+    button_state::{button_press, button_push, button_release},
+    cli::{CliError, ControllerCli},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Instruction {
+    Command(Vec<String>),
+    Wait(String),
+    Hold(Vec<String>),
+    Release(Vec<String>),
+    Set {
+        name: String,
+        expression: Vec<String>,
+    },
+    Jump(usize),
+    JumpUnless {
+        condition: Vec<String>,
+        target: usize,
+    },
+    RepeatStart {
+        count: String,
+        counter: usize,
+        end: usize,
+    },
+    RepeatEnd {
+        counter: usize,
+        start: usize,
+    },
+}
+
+/// Blocks still open while parsing, with the index of their first instruction and line
+enum Block {
+    Repeat { start: usize, line: usize },
+    If { jump: usize, line: usize },
+    Else { jump: usize, line: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    /// Instructions with the line they came from
+    instructions: Vec<(usize, Instruction)>,
+    counters: usize,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, ScriptError> {
+        let mut instructions: Vec<(usize, Instruction)> = vec![];
+        let mut blocks = vec![];
+        let mut labels = HashMap::new();
+        let mut gotos = vec![];
+        let mut counters = 0;
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let error = |kind| ScriptError { line, kind };
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let tokens =
+                shlex::split(text).ok_or_else(|| error(CliError::InvalidQuoting.into()))?;
+            let here = instructions.len();
+            let instruction = match tokens.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                [label] if label.ends_with(':') => {
+                    let label = label.trim_end_matches(':').to_string();
+                    if labels.insert(label.clone(), here).is_some() {
+                        return Err(error(ScriptErrorKind::DuplicateLabel(label)));
+                    }
+                    continue;
+                }
+                ["goto", label] => {
+                    gotos.push((here, line, label.to_string()));
+                    Instruction::Jump(0)
+                }
+                ["wait", time] => Instruction::Wait(time.into()),
+                ["hold", ..] if tokens.len() > 1 => Instruction::Hold(tokens[1..].to_vec()),
+                ["release", ..] if tokens.len() > 1 => Instruction::Release(tokens[1..].to_vec()),
+                ["set", name, "=", ..] if tokens.len() > 3 => Instruction::Set {
+                    name: name.into(),
+                    expression: tokens[3..].to_vec(),
+                },
+                ["repeat", count, "{"] => {
+                    blocks.push(Block::Repeat { start: here, line });
+                    counters += 1;
+                    Instruction::RepeatStart {
+                        count: count.into(),
+                        counter: counters - 1,
+                        end: 0,
+                    }
+                }
+                ["if", .., "{"] if tokens.len() > 2 => {
+                    blocks.push(Block::If { jump: here, line });
+                    Instruction::JumpUnless {
+                        condition: tokens[1..(tokens.len() - 1)].to_vec(),
+                        target: 0,
+                    }
+                }
+                ["}", "else", "{"] => match blocks.pop() {
+                    Some(Block::If { jump, .. }) => {
+                        if let Instruction::JumpUnless { target, .. } = &mut instructions[jump].1 {
+                            *target = here + 1;
+                        }
+                        blocks.push(Block::Else { jump: here, line });
+                        Instruction::Jump(0)
+                    }
+                    _ => return Err(error(ScriptErrorKind::ElseWithoutIf)),
+                },
+                ["}"] => match blocks.pop() {
+                    Some(Block::Repeat { start, .. }) => {
+                        let Instruction::RepeatStart { counter, end, .. } =
+                            &mut instructions[start].1
+                        else {
+                            unreachable!()
+                        };
+                        *end = here + 1;
+                        Instruction::RepeatEnd {
+                            counter: *counter,
+                            start,
+                        }
+                    }
+                    Some(Block::If { jump, .. }) => {
+                        if let Instruction::JumpUnless { target, .. } = &mut instructions[jump].1 {
+                            *target = here;
+                        }
+                        continue;
+                    }
+                    Some(Block::Else { jump, .. }) => {
+                        instructions[jump].1 = Instruction::Jump(here);
+                        continue;
+                    }
+                    None => return Err(error(ScriptErrorKind::UnexpectedBlockEnd)),
+                },
+                _ => Instruction::Command(tokens),
+            };
+            instructions.push((line, instruction));
+        }
+
+        if let Some(block) = blocks.pop() {
+            let (Block::Repeat { line, .. } | Block::If { line, .. } | Block::Else { line, .. }) =
+                block;
+            return Err(ScriptError {
+                line,
+                kind: ScriptErrorKind::UnclosedBlock,
+            });
+        }
+        for (index, line, label) in gotos {
+            let target = *labels.get(&label).ok_or(ScriptError {
+                line,
+                kind: ScriptErrorKind::UnknownLabel(label),
+            })?;
+            instructions[index].1 = Instruction::Jump(target);
+        }
+
+        Ok(Self {
+            instructions,
+            counters,
+        })
+    }
+
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let script = tokio::fs::read_to_string(source).await?;
+        Ok(Self::parse(&script)?)
+    }
+
+    /// Runs the script with the commands of `cli`, stopping at the first error or at `exit`
+    pub async fn run(&self, cli: &mut ControllerCli<'_>) -> Result<(), ScriptError> {
+        let mut variables = HashMap::new();
+        let mut counters = vec![0; self.counters];
+        let mut pc = 0;
+        while let Some((line, instruction)) = self.instructions.get(pc) {
+            let error = |kind| ScriptError { line: *line, kind };
+            let substitute = |tokens: &[String]| -> Result<Vec<String>, ScriptError> {
+                tokens
+                    .iter()
+                    .map(|token| substitute(token, &variables).map_err(error))
+                    .collect()
+            };
+            pc += 1;
+            match instruction {
+                Instruction::Command(tokens) => {
+                    let mut args = substitute(tokens)?;
+                    let cmd = args.remove(0);
+                    match cmd.as_str() {
+                        "exit" => return Ok(()),
+                        "source" => return Err(error(ScriptErrorKind::NestedSource)),
+                        _ if cli.has_command(&cmd)
+                            || cli
+                                .get_controller_state_mut()
+                                .button_state
+                                .resolve(&cmd)
+                                .is_err() =>
+                        {
+                            let result = cli
+                                .execute(&cmd, args)
+                                .await
+                                .map_err(|why| error(why.into()))?;
+                            if !result.is_empty() {
+                                println!("{}", result);
+                            }
+                        }
+                        _ => {
+                            args.insert(0, cmd);
+                            button_push(cli.get_controller_state_mut(), &args, None)
+                                .await
+                                .map_err(|why| error(CliError::from(why).into()))?;
+                        }
+                    }
+                }
+                Instruction::Wait(time) => {
+                    let time = substitute(std::slice::from_ref(time))?.remove(0);
+                    match parse_wait(&time)
+                        .ok_or_else(|| error(ScriptErrorKind::InvalidWait(time)))?
+                    {
+                        Wait::Time(duration) => {
+                            cli.get_controller_state_mut().sleep(duration).await
+                        }
+                        Wait::Frames(frames) => {
+                            cli.get_controller_state_mut()
+                                .wait_for_reports(frames)
+                                .await
+                        }
+                    }
+                }
+                Instruction::Hold(args) | Instruction::Release(args) => {
+                    let args = substitute(args)?;
+                    let controller_state = cli.get_controller_state_mut();
+                    let all_buttons = args
+                        .iter()
+                        .all(|arg| controller_state.button_state.resolve(arg).is_ok());
+                    let result = match instruction {
+                        // "hold <button> <duration>" is the CLI command
+                        Instruction::Hold(_) if !all_buttons => {
+                            cli.execute("hold", args).await.map(|_| ())
+                        }
+                        Instruction::Hold(_) => button_press(controller_state, &args)
+                            .await
+                            .map_err(Into::into),
+                        _ => button_release(controller_state, &args)
+                            .await
+                            .map_err(Into::into),
+                    };
+                    result.map_err(|why| error(why.into()))?;
+                }
+                Instruction::Set { name, expression } => {
+                    let value = evaluate(&substitute(expression)?).map_err(error)?;
+                    variables.insert(name.clone(), value);
+                }
+                Instruction::Jump(target) => {
+                    if *target < pc {
+                        // A loop of set or goto lines never awaits, let the runtime cancel it
+                        tokio::task::yield_now().await;
+                    }
+                    pc = *target
+                }
+                Instruction::JumpUnless { condition, target } => {
+                    let value = evaluate(&substitute(condition)?).map_err(error)?;
+                    if !is_true(&value) {
+                        pc = *target;
+                    }
+                }
+                Instruction::RepeatStart {
+                    count,
+                    counter,
+                    end,
+                } => {
+                    let count = substitute(std::slice::from_ref(count))?.remove(0);
+                    let count = count
+                        .parse::<i64>()
+                        .map_err(|_| error(ScriptErrorKind::InvalidExpression(count)))?;
+                    if count > 0 {
+                        counters[*counter] = count;
+                    } else {
+                        pc = *end;
+                    }
+                }
+                Instruction::RepeatEnd { counter, start } => {
+                    counters[*counter] -= 1;
+                    if counters[*counter] > 0 {
+                        tokio::task::yield_now().await;
+                        pc = start + 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Wait {
+    Time(Duration),
+    Frames(u32),
+}
+
+fn parse_wait(time: &str) -> Option<Wait> {
+    if let Some(ms) = time.strip_suffix("ms") {
+        Some(Wait::Time(Duration::from_millis(ms.parse().ok()?)))
+    } else if let Some(seconds) = time.strip_suffix('s') {
+        Some(Wait::Time(
+            Duration::try_from_secs_f32(seconds.parse().ok()?).ok()?,
+        ))
+    } else if let Some(frames) = time.strip_suffix('f') {
+        Some(Wait::Frames(frames.parse().ok()?))
+    } else {
+        None
+    }
+}
+
+/// Replaces `$name` with the value of the variable
+fn substitute(token: &str, variables: &HashMap<String, String>) -> Result<String, ScriptErrorKind> {
+    let mut result = String::with_capacity(token.len());
+    let mut rest = token;
+    while let Some(pos) = rest.find('$') {
+        result += &rest[..pos];
+        let name_len = rest[(pos + 1)..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len() - pos - 1);
+        let name = &rest[(pos + 1)..(pos + 1 + name_len)];
+        result += variables
+            .get(name)
+            .ok_or_else(|| ScriptErrorKind::UndefinedVariable(name.into()))?;
+        rest = &rest[(pos + 1 + name_len)..];
+    }
+    result += rest;
+    Ok(result)
+}
+
+fn evaluate(expression: &[String]) -> Result<String, ScriptErrorKind> {
+    let invalid = || ScriptErrorKind::InvalidExpression(expression.join(" "));
+    let (left, operator, right) = match expression {
+        [value] => return Ok(value.clone()),
+        [left, operator, right] => (left, operator.as_str(), right),
+        _ => return Err(invalid()),
+    };
+    let numbers = left.parse::<i64>().ok().zip(right.parse::<i64>().ok());
+    let value = match (operator, numbers) {
+        ("==", None) => (left == right) as i64,
+        ("!=", None) => (left != right) as i64,
+        (_, None) => return Err(invalid()),
+        ("+", Some((l, r))) => l.checked_add(r).ok_or_else(invalid)?,
+        ("-", Some((l, r))) => l.checked_sub(r).ok_or_else(invalid)?,
+        ("*", Some((l, r))) => l.checked_mul(r).ok_or_else(invalid)?,
+        ("/", Some((l, r))) => l.checked_div(r).ok_or_else(invalid)?,
+        ("%", Some((l, r))) => l.checked_rem(r).ok_or_else(invalid)?,
+        ("==", Some((l, r))) => (l == r) as i64,
+        ("!=", Some((l, r))) => (l != r) as i64,
+        ("<", Some((l, r))) => (l < r) as i64,
+        ("<=", Some((l, r))) => (l <= r) as i64,
+        (">", Some((l, r))) => (l > r) as i64,
+        (">=", Some((l, r))) => (l >= r) as i64,
+        _ => return Err(invalid()),
+    };
+    Ok(value.to_string())
+}
+
+#[inline]
+fn is_true(value: &str) -> bool {
+    !(value.is_empty() || value == "0" || value == "false")
+}
+
+#[derive(Debug, Error)]
+pub enum ScriptErrorKind {
+    #[error("\"}}\" without an open block")]
+    UnexpectedBlockEnd,
+    #[error("block is never closed")]
+    UnclosedBlock,
+    #[error("\"else\" without \"if\"")]
+    ElseWithoutIf,
+    #[error("label {0} is defined twice")]
+    DuplicateLabel(String),
+    #[error("unknown label {0}")]
+    UnknownLabel(String),
+    #[error("undefined variable {0}")]
+    UndefinedVariable(String),
+    #[error("invalid expression \"{0}\"")]
+    InvalidExpression(String),
+    #[error("invalid wait time \"{0}\", expected e.g. 100ms, 2s or 3f")]
+    InvalidWait(String),
+    #[error("scripts can't source other scripts")]
+    NestedSource,
+    #[error(transparent)]
+    Cli(#[from] CliError),
+}
+
+#[derive(Debug, Error)]
+pub struct ScriptError {
+    pub line: usize,
+    pub kind: ScriptErrorKind,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::connected_state;
+
+    fn strings(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    fn parse_error(source: &str) -> (usize, ScriptErrorKind) {
+        let error = Script::parse(source).unwrap_err();
+        (error.line, error.kind)
+    }
+
+    /// Runs `source` on a Pro Controller, returns the result and the sent reports
+    async fn run(source: &str) -> (Result<(), ScriptError>, Vec<Vec<u8>>) {
+        let (mut controller_state, transport) = connected_state();
+        let mut cli = ControllerCli::new(&mut controller_state);
+        let result = Script::parse(source).unwrap().run(&mut cli).await;
+        (result, transport.take_reports())
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_line() {
+        let script = Script::parse(
+            "# comment\n\
+            \n\
+            stick l \"up\"\n\
+            wait 3f\n\
+            hold a b\n\
+            release a\n\
+            set n = $n + 1\n\
+            start:\n\
+            goto start",
+        )
+        .unwrap();
+        assert_eq!(
+            script.instructions,
+            [
+                (3, Instruction::Command(strings(&["stick", "l", "up"]))),
+                (4, Instruction::Wait("3f".into())),
+                (5, Instruction::Hold(strings(&["a", "b"]))),
+                (6, Instruction::Release(strings(&["a"]))),
+                (
+                    7,
+                    Instruction::Set {
+                        name: "n".into(),
+                        expression: strings(&["$n", "+", "1"]),
+                    }
+                ),
+                (9, Instruction::Jump(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn blocks_jump_past_their_end() {
+        let script = Script::parse(
+            "repeat 2 {\n\
+            a\n\
+            }\n\
+            if $x == 1 {\n\
+            b\n\
+            } else {\n\
+            y\n\
+            }",
+        )
+        .unwrap();
+        let instructions = script
+            .instructions
+            .into_iter()
+            .map(|(_, instruction)| instruction)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            instructions,
+            [
+                Instruction::RepeatStart {
+                    count: "2".into(),
+                    counter: 0,
+                    end: 3,
+                },
+                Instruction::Command(strings(&["a"])),
+                Instruction::RepeatEnd {
+                    counter: 0,
+                    start: 0,
+                },
+                Instruction::JumpUnless {
+                    condition: strings(&["$x", "==", "1"]),
+                    target: 6,
+                },
+                Instruction::Command(strings(&["b"])),
+                Instruction::Jump(7),
+                Instruction::Command(strings(&["y"])),
+            ]
+        );
+        assert_eq!(script.counters, 1);
+    }
+
+    #[test]
+    fn parse_errors_have_the_line() {
+        assert!(matches!(
+            parse_error("a\n\nb \"c"),
+            (3, ScriptErrorKind::Cli(CliError::InvalidQuoting))
+        ));
+        assert!(matches!(
+            parse_error("a\n}"),
+            (2, ScriptErrorKind::UnexpectedBlockEnd)
+        ));
+        assert!(matches!(
+            parse_error("a\nrepeat 3 {\nb"),
+            (2, ScriptErrorKind::UnclosedBlock)
+        ));
+        assert!(matches!(
+            parse_error("repeat 3 {\n} else {\n}"),
+            (2, ScriptErrorKind::ElseWithoutIf)
+        ));
+        assert!(matches!(
+            parse_error("x:\na\nx:"),
+            (3, ScriptErrorKind::DuplicateLabel(label)) if label == "x"
+        ));
+        assert!(matches!(
+            parse_error("a\ngoto nowhere"),
+            (2, ScriptErrorKind::UnknownLabel(label)) if label == "nowhere"
+        ));
+    }
+
+    #[tokio::test]
+    async fn runtime_errors_have_the_line() {
+        let (result, _) = run("wait 1f\nfrobnicate 3").await;
+        let error = result.unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(matches!(
+            error.kind,
+            ScriptErrorKind::Cli(CliError::UnknownCommand(cmd)) if cmd == "frobnicate"
+        ));
+
+        let (result, _) = run("set x = 1\nwait $x").await;
+        let error = result.unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, ScriptErrorKind::InvalidWait(time) if time == "1"));
+
+        let (result, _) = run("\nset x = $y + 1").await;
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "line 2: undefined variable y");
+    }
+
+    #[tokio::test]
+    async fn loops_and_conditions_drive_the_buttons() {
+        let (result, reports) = run("set n = 0\n\
+            repeat 2 {\n\
+            set n = $n + 1\n\
+            if $n == 2 {\n\
+            hold b\n\
+            } else {\n\
+            hold a\n\
+            }\n\
+            wait 2f\n\
+            release a b\n\
+            }\n\
+            exit\n\
+            hold x")
+        .await;
+        result.unwrap();
+        // A is 0x10 and B 0x20 of the first button byte, X is never pressed
+        let buttons = reports.iter().map(|report| report[4]).collect::<Vec<_>>();
+        assert_eq!(buttons, [0x10, 0x10, 0x10, 0x00, 0x20, 0x20, 0x20, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn loops_without_waits_can_be_stopped() {
+        for source in ["x:\ngoto x", "repeat 1000000000 {\nset n = 1\n}"] {
+            let stopped = tokio::time::timeout(Duration::from_millis(50), run(source)).await;
+            assert!(stopped.is_err(), "{:?} ended", source);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StickCalibration {
     pub h_center: u32,
     pub v_center: u32,
@@ -16,14 +16,28 @@ impl Display for StickCalibration {
     }
 }
 
+/// Two 12 bit values packed into 3 bytes, the layout used by all stick data in the SPI flash
+#[inline]
+fn unpack_pair(bytes: &[u8]) -> (u32, u32) {
+    let first = ((bytes[1] as u32) << 8) & 0xF00 | (bytes[0] as u32);
+    let second = ((bytes[2] as u32) << 4) | ((bytes[1] as u32) >> 4);
+    (first, second)
+}
+
+#[inline]
+fn pack_pair(first: u32, second: u32) -> [u8; 3] {
+    [
+        first as u8,
+        (((first >> 8) & 0xF) | ((second & 0xF) << 4)) as u8,
+        (second >> 4) as u8,
+    ]
+}
+
 impl StickCalibration {
     pub fn l_from_bytes(bytes: &[u8; 9]) -> Self {
-        let h_max_above_center: u32 = ((bytes[1] as u32) << 8) & 0xF00 | (bytes[0] as u32);
-        let v_max_above_center: u32 = ((bytes[2] as u32) << 4) | ((bytes[1] as u32) >> 4);
-        let h_center: u32 = ((bytes[4] as u32) << 8) & 0xF00 | (bytes[3] as u32);
-        let v_center: u32 = ((bytes[5] as u32) << 4) | ((bytes[4] as u32) >> 4);
-        let h_max_below_center: u32 = ((bytes[7] as u32) << 8) & 0xF00 | bytes[6] as u32;
-        let v_max_below_center: u32 = ((bytes[8] as u32) << 4) | ((bytes[7] as u32) >> 4);
+        let (h_max_above_center, v_max_above_center) = unpack_pair(&bytes[0..3]);
+        let (h_center, v_center) = unpack_pair(&bytes[3..6]);
+        let (h_max_below_center, v_max_below_center) = unpack_pair(&bytes[6..9]);
 
         Self {
             h_center,
@@ -36,12 +50,9 @@ impl StickCalibration {
     }
 
     pub fn r_from_bytes(bytes: &[u8; 9]) -> Self {
-        let h_center: u32 = ((bytes[1] as u32) << 8) & 0xF00 | (bytes[0] as u32);
-        let v_center: u32 = ((bytes[2] as u32) << 4) | ((bytes[1] as u32) >> 4);
-        let h_max_below_center: u32 = ((bytes[4] as u32) << 8) & 0xF00 | (bytes[3] as u32);
-        let v_max_below_center: u32 = ((bytes[5] as u32) << 4) | ((bytes[4] as u32) >> 4);
-        let h_max_above_center: u32 = ((bytes[7] as u32) << 8) & 0xF00 | bytes[6] as u32;
-        let v_max_above_center: u32 = ((bytes[8] as u32) << 4) | ((bytes[7] as u32) >> 4);
+        let (h_center, v_center) = unpack_pair(&bytes[0..3]);
+        let (h_max_below_center, v_max_below_center) = unpack_pair(&bytes[3..6]);
+        let (h_max_above_center, v_max_above_center) = unpack_pair(&bytes[6..9]);
 
         Self {
             h_center,
@@ -52,4 +63,128 @@ impl StickCalibration {
             v_max_below_center,
         }
     }
+
+    /// Inverse of `l_from_bytes`, values are truncated to 12 bits
+    pub fn l_to_bytes(&self) -> [u8; 9] {
+        let mut result = [0; 9];
+        result[0..3].copy_from_slice(&pack_pair(self.h_max_above_center, self.v_max_above_center));
+        result[3..6].copy_from_slice(&pack_pair(self.h_center, self.v_center));
+        result[6..9].copy_from_slice(&pack_pair(self.h_max_below_center, self.v_max_below_center));
+        result
+    }
+
+    /// Inverse of `r_from_bytes`, values are truncated to 12 bits
+    pub fn r_to_bytes(&self) -> [u8; 9] {
+        let mut result = [0; 9];
+        result[0..3].copy_from_slice(&pack_pair(self.h_center, self.v_center));
+        result[3..6].copy_from_slice(&pack_pair(self.h_max_below_center, self.v_max_below_center));
+        result[6..9].copy_from_slice(&pack_pair(self.h_max_above_center, self.v_max_above_center));
+        result
+    }
+}
+
+/// Stick device parameters stored at 0x6086 (left) and 0x6098 (right) in the SPI flash: 12 packed
+/// 12 bit values, of which only the deadzone and the range ratio are known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StickDeviceParameters {
+    values: [u32; 12],
+}
+
+impl Display for StickDeviceParameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "deadzone:{} range_ratio:{}",
+            self.get_deadzone(),
+            self.get_range_ratio()
+        )
+    }
+}
+
+impl StickDeviceParameters {
+    pub fn from_bytes(bytes: &[u8; 18]) -> Self {
+        let mut values = [0; 12];
+        for (i, chunk) in bytes.chunks_exact(3).enumerate() {
+            (values[i * 2], values[i * 2 + 1]) = unpack_pair(chunk);
+        }
+        Self { values }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 18] {
+        let mut result = [0; 18];
+        for (i, pair) in self.values.chunks_exact(2).enumerate() {
+            result[(i * 3)..(i * 3 + 3)].copy_from_slice(&pack_pair(pair[0], pair[1]));
+        }
+        result
+    }
+
+    #[inline]
+    pub fn get_deadzone(&self) -> u32 {
+        self.values[2]
+    }
+
+    #[inline]
+    pub fn set_deadzone(&mut self, deadzone: u32) {
+        self.values[2] = deadzone & 0xFFF
+    }
+
+    #[inline]
+    pub fn get_range_ratio(&self) -> u32 {
+        self.values[3]
+    }
+
+    #[inline]
+    pub fn set_range_ratio(&mut self, range_ratio: u32) {
+        self.values[3] = range_ratio & 0xFFF
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every 12 bit value in every field, with different values in the fields sharing bytes
+    fn calibrations() -> impl Iterator<Item = StickCalibration> {
+        (0..0x1000).map(|value| StickCalibration {
+            h_center: value,
+            v_center: 0xFFF - value,
+            h_max_above_center: (value + 0x555) & 0xFFF,
+            v_max_above_center: (value * 7) & 0xFFF,
+            h_max_below_center: value ^ 0xA5A,
+            v_max_below_center: (value + 1) & 0xFFF,
+        })
+    }
+
+    #[test]
+    fn calibration_round_trips() {
+        for calibration in calibrations() {
+            assert_eq!(
+                StickCalibration::l_from_bytes(&calibration.l_to_bytes()),
+                calibration
+            );
+            assert_eq!(
+                StickCalibration::r_from_bytes(&calibration.r_to_bytes()),
+                calibration
+            );
+        }
+    }
+
+    #[test]
+    fn device_parameters_round_trip() {
+        let mut bytes = [0; 18];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 37) as u8;
+        }
+        let mut parameters = StickDeviceParameters::from_bytes(&bytes);
+        assert_eq!(parameters.to_bytes(), bytes);
+
+        for value in 0..0x1000 {
+            parameters.set_deadzone(value);
+            parameters.set_range_ratio(0xFFF - value);
+            let round_trip = StickDeviceParameters::from_bytes(&parameters.to_bytes());
+            assert_eq!(round_trip.get_deadzone(), value);
+            assert_eq!(round_trip.get_range_ratio(), 0xFFF - value);
+            assert_eq!(round_trip, parameters);
+        }
+    }
 }
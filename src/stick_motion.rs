@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use strum::EnumString;
+
+use crate::stick_state::{NoCalibrationDataAvailable, StickState};
+
+/// Fraction of a flick spent moving out to the edge, the same time is spent on the way back
+const FLICK_EDGE: f32 = 0.25;
+
+/// Maps the linear progress of a motion in [0, 1] to the eased progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => t * (2.0 - t),
+            Self::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MotionKind {
+    Ramp {
+        to: (f32, f32),
+        easing: Easing,
+    },
+    Circle {
+        start_angle: f32,
+        turns: f32,
+        magnitude: f32,
+    },
+    Flick {
+        to: (f32, f32),
+    },
+}
+
+/// Time-based stick movement in normalized coordinates (see `StickState::set_normalized`). The
+/// controller state advances it once per input report, so every report gets an intermediate
+/// position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StickMotion {
+    kind: MotionKind,
+    duration: Duration,
+    elapsed: Duration,
+    /// Position of the stick when the motion started, set on the first advance
+    start: Option<(f32, f32)>,
+}
+
+impl StickMotion {
+    fn new(kind: MotionKind, duration: Duration) -> Self {
+        Self {
+            kind,
+            duration,
+            elapsed: Duration::ZERO,
+            start: None,
+        }
+    }
+
+    /// Moves from the current position to `to`
+    pub fn ramp(to: (f32, f32), duration: Duration, easing: Option<Easing>) -> Self {
+        Self::new(
+            MotionKind::Ramp {
+                to,
+                easing: easing.unwrap_or_default(),
+            },
+            duration,
+        )
+    }
+
+    /// Spins the stick around at `magnitude`, starting at `start_angle` degrees. Negative `turns`
+    /// spin clockwise.
+    pub fn circle(start_angle: f32, turns: f32, magnitude: f32, duration: Duration) -> Self {
+        Self::new(
+            MotionKind::Circle {
+                start_angle: start_angle.to_radians(),
+                turns,
+                magnitude: magnitude.clamp(0.0, 1.0),
+            },
+            duration,
+        )
+    }
+
+    /// Quickly pushes the stick to full deflection at `angle` degrees, holds it and lets it snap
+    /// back to the center
+    pub fn flick(angle: f32, duration: Duration) -> Self {
+        let angle = angle.to_radians();
+        Self::new(
+            MotionKind::Flick {
+                to: (angle.cos(), angle.sin()),
+            },
+            duration,
+        )
+    }
+
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    /// Position at `t` in [0, 1] of the motion
+    fn position_at(&self, t: f32) -> (f32, f32) {
+        let (start_x, start_y) = self.start.unwrap_or_default();
+        let lerp = |(to_x, to_y): (f32, f32), t: f32| {
+            (
+                start_x + (to_x - start_x) * t,
+                start_y + (to_y - start_y) * t,
+            )
+        };
+        match self.kind {
+            MotionKind::Ramp { to, easing } => lerp(to, easing.apply(t)),
+            MotionKind::Circle {
+                start_angle,
+                turns,
+                magnitude,
+            } => {
+                let angle = start_angle + turns * std::f32::consts::TAU * t;
+                (magnitude * angle.cos(), magnitude * angle.sin())
+            }
+            MotionKind::Flick { to: (to_x, to_y) } => {
+                let deflection = if t < FLICK_EDGE {
+                    Easing::EaseOut.apply(t / FLICK_EDGE)
+                } else if t <= 1.0 - FLICK_EDGE {
+                    1.0
+                } else {
+                    1.0 - Easing::EaseIn.apply((t - (1.0 - FLICK_EDGE)) / FLICK_EDGE)
+                };
+                (to_x * deflection, to_y * deflection)
+            }
+        }
+    }
+
+    /// Moves `stick` to the position `elapsed` after the previous call. The first call only
+    /// records the start position, so the first report still shows where the stick was.
+    pub fn advance(
+        &mut self,
+        stick: &mut StickState,
+        elapsed: Duration,
+    ) -> Result<(), NoCalibrationDataAvailable> {
+        if self.start.is_none() {
+            self.start = Some(stick.get_normalized()?);
+        } else {
+            self.elapsed = (self.elapsed + elapsed).min(self.duration);
+        }
+        let t = if self.duration.is_zero() {
+            1.0
+        } else {
+            self.elapsed.as_secs_f32() / self.duration.as_secs_f32()
+        };
+        let (x, y) = self.position_at(t);
+        stick.set_normalized(x, y)
+    }
+}
@@ -0,0 +1,196 @@
+use std::{error::Error, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Imperfections added to the reported stick position, to imitate a worn stick. All values are
+/// in raw stick units. Example config:
+/// ```yaml
+/// jitter: 8
+/// drift: [3.0, -1.5]
+/// drift_max: 120
+/// quantization: 4
+/// seed: 42
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StickNoiseConfig {
+    /// While the stick rests at its calibrated center, each report is offset by up to this much on
+    /// each axis
+    pub jitter: f32,
+    /// (horizontal, vertical) offset added per second
+    pub drift: (f32, f32),
+    /// Largest distance the drift moves the stick away from its position
+    pub drift_max: f32,
+    /// Reported values are rounded down to multiples of this, 0 or 1 disables it
+    pub quantization: u32,
+    /// Makes the noise reproducible, a random seed is used otherwise
+    pub seed: Option<u64>,
+}
+
+impl Default for StickNoiseConfig {
+    fn default() -> Self {
+        Self {
+            jitter: 0.0,
+            drift: (0.0, 0.0),
+            drift_max: f32::INFINITY,
+            quantization: 0,
+            seed: None,
+        }
+    }
+}
+
+impl StickNoiseConfig {
+    pub fn from_yaml(config: &str) -> Result<Self, StickNoiseConfigError> {
+        let config: Self = serde_yaml::from_str(config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Jitter and drift_max have to be positive or 0, the drift finite
+    pub fn validate(&self) -> Result<(), StickNoiseConfigError> {
+        if !(self.jitter.is_finite() && self.jitter >= 0.0) {
+            Err(StickNoiseConfigError::InvalidValue("jitter", self.jitter))
+        } else if !(self.drift.0.is_finite() && self.drift.1.is_finite()) {
+            Err(StickNoiseConfigError::InvalidValue(
+                "drift",
+                if self.drift.0.is_finite() {
+                    self.drift.1
+                } else {
+                    self.drift.0
+                },
+            ))
+        } else if self.drift_max.is_nan() || self.drift_max < 0.0 {
+            Err(StickNoiseConfigError::InvalidValue(
+                "drift_max",
+                self.drift_max,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let config = tokio::fs::read_to_string(source).await?;
+        Ok(Self::from_yaml(&config)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StickNoise {
+    config: StickNoiseConfig,
+    rng: StdRng,
+    /// Drift accumulated so far
+    drift: (f32, f32),
+}
+
+impl StickNoise {
+    pub fn new(config: StickNoiseConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            config,
+            rng,
+            drift: (0.0, 0.0),
+        }
+    }
+
+    #[inline]
+    pub fn get_config(&self) -> &StickNoiseConfig {
+        &self.config
+    }
+
+    /// Clears the drift and restarts the random sequence, so a seeded run can be repeated
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config.clone())
+    }
+
+    /// Returns the raw (h, v) to report `elapsed` after the previous report. Jitter is only added
+    /// if the stick is `at_center`.
+    pub fn apply(&mut self, h: u32, v: u32, at_center: bool, elapsed: Duration) -> (u32, u32) {
+        let seconds = elapsed.as_secs_f32();
+        let drift_h = self.drift.0 + self.config.drift.0 * seconds;
+        let drift_v = self.drift.1 + self.config.drift.1 * seconds;
+        let length = drift_h.hypot(drift_v);
+        self.drift = if length > self.config.drift_max {
+            let scale = self.config.drift_max / length;
+            (drift_h * scale, drift_v * scale)
+        } else {
+            (drift_h, drift_v)
+        };
+
+        let jitter = self.config.jitter;
+        // Checked again, the config fields can be set without validation
+        let (jitter_h, jitter_v) = if at_center && jitter.is_finite() && jitter > 0.0 {
+            (
+                self.rng.gen_range(-jitter..=jitter),
+                self.rng.gen_range(-jitter..=jitter),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        let quantization = self.config.quantization.max(1);
+        let output = |value: u32, offset: f32| {
+            let value = (value as f32 + offset).round().clamp(0.0, 0xFFF as f32) as u32;
+            value - value % quantization
+        };
+        (
+            output(h, self.drift.0 + jitter_h),
+            output(v, self.drift.1 + jitter_v),
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StickNoiseConfigError {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Invalid stick noise {0} {1}.")]
+    InvalidValue(&'static str, f32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for config in [
+            "jitter: -1",
+            "jitter: .nan",
+            "jitter: .inf",
+            "drift: [.nan, 0]",
+            "drift_max: -5",
+        ] {
+            assert!(matches!(
+                StickNoiseConfig::from_yaml(config),
+                Err(StickNoiseConfigError::InvalidValue(..))
+            ));
+        }
+        assert_eq!(
+            StickNoiseConfig::from_yaml("jitter: 8\ndrift_max: .inf")
+                .unwrap()
+                .jitter,
+            8.0
+        );
+    }
+
+    #[test]
+    fn jitter_only_applies_at_the_center() {
+        let config = StickNoiseConfig::from_yaml("jitter: 50\nseed: 1").unwrap();
+        let mut noise = StickNoise::new(config);
+        let elapsed = Duration::from_millis(16);
+        assert!((0..100).all(|_| noise.apply(3000, 1000, false, elapsed) == (3000, 1000)));
+        let jittered = (0..100)
+            .map(|_| noise.apply(2048, 2048, true, elapsed))
+            .collect::<Vec<_>>();
+        assert!(jittered.iter().any(|&position| position != (2048, 2048)));
+        assert!(jittered
+            .iter()
+            .all(|&(h, v)| h.abs_diff(2048) <= 50 && v.abs_diff(2048) <= 50));
+    }
+}
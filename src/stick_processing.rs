@@ -0,0 +1,272 @@
+use std::error::Error;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Shape of the area the stick can move in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickGate {
+    /// Output is limited to the unit circle, like the real Joy-Con gate
+    #[default]
+    Circular,
+    /// Each axis is limited separately, so corners reach full deflection on both axes
+    Square,
+}
+
+/// Maps the deflection after the deadzones (in [0, 1]) to the output deflection
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ResponseCurve {
+    #[default]
+    Linear,
+    /// `deflection ^ exponent`, exponents above 1 give finer control near the center
+    Exponential { exponent: f32 },
+    /// Piecewise linear curve through (input, output) points
+    Custom { points: Vec<(f32, f32)> },
+}
+
+impl ResponseCurve {
+    pub fn eval(&self, deflection: f32) -> f32 {
+        match self {
+            Self::Linear => deflection,
+            Self::Exponential { exponent } => deflection.powf(*exponent),
+            Self::Custom { points } => {
+                let after = points.iter().position(|(input, _)| *input >= deflection);
+                match after {
+                    None => points.last().map_or(deflection, |(_, output)| *output),
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let (x0, y0) = points[i - 1];
+                        let (x1, y1) = points[i];
+                        y0 + (deflection - x0) / (x1 - x0) * (y1 - y0)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Processing applied to normalized stick input before it is set on a `StickState`, see
+/// `StickState::set_processor`. All values are fractions of full deflection. Example config:
+/// ```yaml
+/// inner_deadzone: 0.1
+/// outer_deadzone: 0.05
+/// anti_deadzone: 0.2
+/// gate: circular
+/// curve:
+///   type: exponential
+///   exponent: 1.5
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StickProcessor {
+    /// Input below this deflection is reported as centered
+    pub inner_deadzone: f32,
+    /// Input within this distance of full deflection is reported as full deflection
+    pub outer_deadzone: f32,
+    /// Smallest deflection reported outside of the inner deadzone, to get past the game's own
+    /// deadzone
+    pub anti_deadzone: f32,
+    pub gate: StickGate,
+    pub curve: ResponseCurve,
+}
+
+impl Default for StickProcessor {
+    fn default() -> Self {
+        Self {
+            inner_deadzone: 0.0,
+            outer_deadzone: 0.0,
+            anti_deadzone: 0.0,
+            gate: StickGate::default(),
+            curve: ResponseCurve::default(),
+        }
+    }
+}
+
+impl StickProcessor {
+    pub fn from_yaml(config: &str) -> Result<Self, StickProcessorError> {
+        let mut processor: Self = serde_yaml::from_str(config)?;
+        processor.validate()?;
+        if let ResponseCurve::Custom { points } = &mut processor.curve {
+            points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        Ok(processor)
+    }
+
+    /// Deadzones have to be in [0, 1) and leave some range between them, exponents and curve
+    /// points have to be finite and exponents positive
+    pub fn validate(&self) -> Result<(), StickProcessorError> {
+        let deadzones = [
+            ("inner_deadzone", self.inner_deadzone),
+            ("outer_deadzone", self.outer_deadzone),
+            ("anti_deadzone", self.anti_deadzone),
+        ];
+        if let Some((name, value)) = deadzones
+            .into_iter()
+            .find(|(_, value)| !(0.0..1.0).contains(value))
+        {
+            return Err(StickProcessorError::InvalidValue(name, value));
+        }
+        if self.inner_deadzone + self.outer_deadzone >= 1.0 {
+            return Err(StickProcessorError::InvalidValue(
+                "outer_deadzone",
+                self.outer_deadzone,
+            ));
+        }
+        match &self.curve {
+            ResponseCurve::Linear => Ok(()),
+            ResponseCurve::Exponential { exponent } => {
+                if exponent.is_finite() && *exponent > 0.0 {
+                    Ok(())
+                } else {
+                    Err(StickProcessorError::InvalidValue("exponent", *exponent))
+                }
+            }
+            ResponseCurve::Custom { points } => points
+                .iter()
+                .flat_map(|(input, output)| [*input, *output])
+                .find(|value| !value.is_finite())
+                .map_or(Ok(()), |value| {
+                    Err(StickProcessorError::InvalidValue("curve point", value))
+                }),
+        }
+    }
+
+    pub async fn load(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let config = tokio::fs::read_to_string(source).await?;
+        Ok(Self::from_yaml(&config)?)
+    }
+
+    /// Processes normalized (x, y) input, see `StickState::set_normalized`
+    pub fn process(&self, x: f32, y: f32) -> (f32, f32) {
+        let magnitude = x.hypot(y);
+        if magnitude == 0.0 {
+            return (0.0, 0.0);
+        }
+        let (direction_x, direction_y) = (x / magnitude, y / magnitude);
+
+        // Deflection is measured relative to the edge of the gate in the stick's direction
+        let gate_edge = match self.gate {
+            StickGate::Circular => 1.0,
+            StickGate::Square => 1.0 / direction_x.abs().max(direction_y.abs()),
+        };
+        let deflection = (magnitude / gate_edge).min(1.0);
+        if deflection <= self.inner_deadzone {
+            return (0.0, 0.0);
+        }
+
+        let range = (1.0 - self.outer_deadzone - self.inner_deadzone).max(f32::EPSILON);
+        let deflection = ((deflection - self.inner_deadzone) / range).min(1.0);
+        let shaped = self.curve.eval(deflection).clamp(0.0, 1.0);
+        let output = (self.anti_deadzone + (1.0 - self.anti_deadzone) * shaped) * gate_edge;
+
+        (
+            (direction_x * output).clamp(-1.0, 1.0),
+            (direction_y * output).clamp(-1.0, 1.0),
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StickProcessorError {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Invalid stick processing {0} {1}.")]
+    InvalidValue(&'static str, f32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stick_calibration::StickCalibration, stick_state::StickState};
+
+    fn assert_close((x, y): (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (x - expected.0).abs() < 1e-4 && (y - expected.1).abs() < 1e-4,
+            "{:?} != {:?}",
+            (x, y),
+            expected
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for config in [
+            "inner_deadzone: .nan",
+            "outer_deadzone: -0.1",
+            "anti_deadzone: 1",
+            "inner_deadzone: 0.6\nouter_deadzone: 0.4",
+            "curve:\n  type: exponential\n  exponent: 0",
+            "curve:\n  type: custom\n  points: [[0, 0], [.nan, 1]]",
+        ] {
+            assert!(
+                matches!(
+                    StickProcessor::from_yaml(config),
+                    Err(StickProcessorError::InvalidValue(..))
+                ),
+                "{}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn deadzones_rescale_the_deflection() {
+        let processor = StickProcessor::from_yaml(
+            "inner_deadzone: 0.2\nouter_deadzone: 0.2\nanti_deadzone: 0.1",
+        )
+        .unwrap();
+        assert_close(processor.process(0.2, 0.0), (0.0, 0.0));
+        // Just outside of the inner deadzone is the anti-deadzone
+        assert_close(processor.process(0.0, -0.2001), (0.0, -0.1002));
+        assert_close(processor.process(0.5, 0.0), (0.55, 0.0));
+        assert_close(processor.process(-0.8, 0.0), (-1.0, 0.0));
+        assert_close(processor.process(0.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn gates_limit_the_corners() {
+        let circular = StickProcessor::default();
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(circular.process(1.0, 1.0), (diagonal, diagonal));
+        let square = StickProcessor {
+            gate: StickGate::Square,
+            ..Default::default()
+        };
+        assert_close(square.process(1.0, 1.0), (1.0, 1.0));
+        assert_close(square.process(0.5, 0.25), (0.5, 0.25));
+    }
+
+    #[test]
+    fn curves_shape_the_deflection() {
+        let exponential = ResponseCurve::Exponential { exponent: 2.0 };
+        assert_eq!(exponential.eval(0.5), 0.25);
+        let processor = StickProcessor::from_yaml(
+            "curve:\n  type: custom\n  points: [[1, 1], [0, 0], [0.5, 0.2]]",
+        )
+        .unwrap();
+        assert_close(processor.process(0.25, 0.0), (0.1, 0.0));
+        assert_close(processor.process(0.0, -0.75), (0.0, -0.6));
+    }
+
+    #[test]
+    fn set_normalized_applies_the_processor() {
+        let calibration = StickCalibration {
+            h_center: 2048,
+            v_center: 2048,
+            h_max_above_center: 1000,
+            v_max_above_center: 1000,
+            h_max_below_center: 1000,
+            v_max_below_center: 1000,
+        };
+        let mut stick = StickState::new(None, None, Some(calibration)).unwrap();
+        stick.set_processor(Some(
+            StickProcessor::from_yaml("inner_deadzone: 0.3").unwrap(),
+        ));
+        stick.set_normalized(0.25, 0.0).unwrap();
+        assert_eq!((stick.get_h(), stick.get_v()), (2048, 2048));
+        stick.set_angle(0.0, 1.0).unwrap();
+        assert_eq!((stick.get_h(), stick.get_v()), (3048, 2048));
+    }
+}
@@ -1,9 +1,11 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
 use strum::EnumString;
 use thiserror::Error;
 
-use crate::stick_calibration::StickCalibration;
+use crate::{
+    stick_calibration::StickCalibration, stick_noise::StickNoise, stick_processing::StickProcessor,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString)]
 #[strum(serialize_all = "snake_case")]
@@ -13,6 +15,16 @@ pub enum StickDirection {
     Down,
     Left,
     Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+    /// Angle in degrees and optional magnitude, see `StickState::set_angle`
+    #[strum(serialize = "angle", serialize = "a")]
+    Angle,
+    /// Horizontal and vertical tilt in percent, see `StickState::set_percent`
+    #[strum(serialize = "percent", serialize = "p")]
+    Percent,
     #[strum(to_string = "v")]
     Vertical,
     #[strum(to_string = "h")]
@@ -24,6 +36,8 @@ pub struct StickState {
     h_stick: u32,
     v_stick: u32,
     calibration: Option<StickCalibration>,
+    noise: Option<StickNoise>,
+    processor: Option<StickProcessor>,
 }
 
 impl StickState {
@@ -42,6 +56,8 @@ impl StickState {
                 h_stick: h,
                 v_stick: v,
                 calibration,
+                noise: None,
+                processor: None,
             })
         }
     }
@@ -127,7 +143,7 @@ impl StickState {
 
     pub fn set_right(&mut self) -> Result<(), NoCalibrationDataAvailable> {
         if let Some(calib_data) = &self.calibration {
-            self.h_stick = calib_data.h_center - calib_data.h_max_above_center;
+            self.h_stick = calib_data.h_center + calib_data.h_max_above_center;
             self.v_stick = calib_data.v_center;
             Ok(())
         } else {
@@ -135,6 +151,124 @@ impl StickState {
         }
     }
 
+    /// Diagonals are full deflection on the circular gate, not in the corners of the square
+    #[inline]
+    pub fn set_up_left(&mut self) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_angle(135.0, 1.0)
+    }
+
+    #[inline]
+    pub fn set_up_right(&mut self) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_angle(45.0, 1.0)
+    }
+
+    #[inline]
+    pub fn set_down_left(&mut self) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_angle(225.0, 1.0)
+    }
+
+    #[inline]
+    pub fn set_down_right(&mut self) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_angle(315.0, 1.0)
+    }
+
+    /// Like `set_polar`, but `angle` is in degrees
+    #[inline]
+    pub fn set_angle(
+        &mut self,
+        angle: f32,
+        magnitude: f32,
+    ) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_polar(angle.to_radians(), magnitude)
+    }
+
+    /// Like `set_normalized`, but `x` and `y` are in [-100, 100]
+    #[inline]
+    pub fn set_percent(&mut self, x: f32, y: f32) -> Result<(), NoCalibrationDataAvailable> {
+        self.set_normalized(x / 100.0, y / 100.0)
+    }
+
+    /// Sets the position from coordinates in [-1, 1], positive being right and up. Values outside
+    /// of the range are clamped. The calibration's ranges below and above the center are used for
+    /// the negative and positive halves respectively. The processor, if set, is applied first.
+    pub fn set_normalized(&mut self, x: f32, y: f32) -> Result<(), NoCalibrationDataAvailable> {
+        let calib_data = self.get_calibration()?;
+        let (x, y) = match &self.processor {
+            Some(processor) => processor.process(x, y),
+            None => (x, y),
+        };
+        let h_stick = Self::denormalize(
+            x,
+            calib_data.h_center,
+            calib_data.h_max_below_center,
+            calib_data.h_max_above_center,
+        );
+        let v_stick = Self::denormalize(
+            y,
+            calib_data.v_center,
+            calib_data.v_max_below_center,
+            calib_data.v_max_above_center,
+        );
+        self.h_stick = h_stick;
+        self.v_stick = v_stick;
+        Ok(())
+    }
+
+    /// Inverse of `set_normalized`
+    pub fn get_normalized(&self) -> Result<(f32, f32), NoCalibrationDataAvailable> {
+        let calib_data = self.get_calibration()?;
+        Ok((
+            Self::normalize(
+                self.h_stick,
+                calib_data.h_center,
+                calib_data.h_max_below_center,
+                calib_data.h_max_above_center,
+            ),
+            Self::normalize(
+                self.v_stick,
+                calib_data.v_center,
+                calib_data.v_max_below_center,
+                calib_data.v_max_above_center,
+            ),
+        ))
+    }
+
+    /// `angle` is in radians, counterclockwise starting from right. `magnitude` is clamped to
+    /// [0, 1], a magnitude of 1 is on the unit circle in normalized coordinates.
+    pub fn set_polar(
+        &mut self,
+        angle: f32,
+        magnitude: f32,
+    ) -> Result<(), NoCalibrationDataAvailable> {
+        let magnitude = magnitude.clamp(0.0, 1.0);
+        self.set_normalized(magnitude * angle.cos(), magnitude * angle.sin())
+    }
+
+    /// Inverse of `set_polar`, returns (angle, magnitude)
+    pub fn get_polar(&self) -> Result<(f32, f32), NoCalibrationDataAvailable> {
+        let (x, y) = self.get_normalized()?;
+        Ok((y.atan2(x), x.hypot(y)))
+    }
+
+    fn denormalize(value: f32, center: u32, max_below_center: u32, max_above_center: u32) -> u32 {
+        let value = value.clamp(-1.0, 1.0);
+        let offset = if value >= 0.0 {
+            value * max_above_center as f32
+        } else {
+            value * max_below_center as f32
+        };
+        (center as f32 + offset).round().clamp(0.0, 0xFFF as f32) as u32
+    }
+
+    fn normalize(value: u32, center: u32, max_below_center: u32, max_above_center: u32) -> f32 {
+        let normalized = if value >= center {
+            (value - center) as f32 / max_above_center.max(1) as f32
+        } else {
+            -((center - value) as f32) / max_below_center.max(1) as f32
+        };
+        normalized.clamp(-1.0, 1.0)
+    }
+
     #[inline]
     pub fn set_calibration(&mut self, calibration: StickCalibration) {
         self.calibration = Some(calibration)
@@ -145,11 +279,50 @@ impl StickState {
         self.calibration.as_ref().ok_or(NoCalibrationDataAvailable)
     }
 
+    /// Noise only changes the reported position, not the one set on the stick
+    #[inline]
+    pub fn set_noise(&mut self, noise: Option<StickNoise>) {
+        self.noise = noise
+    }
+
+    /// Processing of the positions set with `set_normalized` and the functions based on it, raw
+    /// values and the center are set as they are
+    #[inline]
+    pub fn set_processor(&mut self, processor: Option<StickProcessor>) {
+        self.processor = processor
+    }
+
+    #[inline]
+    pub fn get_noise_mut(&mut self) -> Option<&mut StickNoise> {
+        self.noise.as_mut()
+    }
+
+    /// Like `as_bytes`, but with the noise applied. Called for every input report with the time
+    /// since the previous one.
+    pub fn as_noisy_bytes(&mut self, elapsed: Duration) -> [u8; 3] {
+        let at_center = self.is_center(None).unwrap_or(false);
+        match &mut self.noise {
+            Some(noise) => {
+                let (h_stick, v_stick) =
+                    noise.apply(self.h_stick, self.v_stick, at_center, elapsed);
+                Self {
+                    h_stick,
+                    v_stick,
+                    calibration: None,
+                    noise: None,
+                    processor: None,
+                }
+                .as_bytes()
+            }
+            None => self.as_bytes(),
+        }
+    }
+
     #[inline]
     pub fn as_bytes(&self) -> [u8; 3] {
         let byte_0 = self.h_stick as u8;
-        let byte_1 = ((self.v_stick as u8) & 0xF) << 4;
-        let byte_2 = (self.v_stick as u8) >> 4;
+        let byte_1 = (((self.h_stick >> 8) & 0xF) | ((self.v_stick & 0xF) << 4)) as u8;
+        let byte_2 = (self.v_stick >> 4) as u8;
         [byte_0, byte_1, byte_2]
     }
 }
@@ -163,6 +336,8 @@ impl From<&[u8; 3]> for StickState {
             h_stick,
             v_stick,
             calibration: None,
+            noise: None,
+            processor: None,
         }
     }
 }
@@ -184,3 +359,46 @@ impl Display for NoCalibrationDataAvailable {
         write!(f, "No calibration data available")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibrated_stick() -> StickState {
+        let calibration = StickCalibration {
+            h_center: 2048,
+            v_center: 2000,
+            h_max_above_center: 1200,
+            v_max_above_center: 1100,
+            h_max_below_center: 1000,
+            v_max_below_center: 900,
+        };
+        StickState::new(None, None, Some(calibration)).unwrap()
+    }
+
+    #[test]
+    fn directions_use_the_range_on_their_side() {
+        let mut stick = calibrated_stick();
+        stick.set_right().unwrap();
+        assert_eq!((stick.get_h(), stick.get_v()), (3248, 2000));
+        assert_eq!(stick.get_normalized().unwrap(), (1.0, 0.0));
+        stick.set_left().unwrap();
+        assert_eq!((stick.get_h(), stick.get_v()), (1048, 2000));
+        assert_eq!(stick.get_normalized().unwrap(), (-1.0, 0.0));
+        stick.set_up().unwrap();
+        assert_eq!(stick.get_normalized().unwrap(), (0.0, 1.0));
+        stick.set_down().unwrap();
+        assert_eq!(stick.get_normalized().unwrap(), (0.0, -1.0));
+    }
+
+    #[test]
+    fn bytes_keep_all_12_bits() {
+        let stick = StickState::new(Some(0xABC), Some(0x123), None).unwrap();
+        assert_eq!(stick.as_bytes(), [0xBC, 0x3A, 0x12]);
+        for value in 0..0x1000 {
+            let stick = StickState::new(Some(value), Some(0xFFF - value), None).unwrap();
+            let unpacked = StickState::from(&stick.as_bytes());
+            assert_eq!((unpacked.get_h(), unpacked.get_v()), (value, 0xFFF - value));
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// Where input reports are written to, the HID interrupt channel once connected
+pub trait ReportTransport: Send {
+    fn write(&mut self, report: &[u8]) -> io::Result<()>;
+}
+
+/// Keeps the written reports in memory, to check what would be sent without a Switch. Clones
+/// share the reports.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackTransport {
+    reports: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl LoopbackTransport {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports written since the last call, oldest first
+    pub fn take_reports(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.reports.lock().unwrap())
+    }
+}
+
+/// Pro Controller with the default SPI flash, writing its reports to the returned transport
+#[cfg(test)]
+pub fn connected_state() -> (crate::controller_state::ControllerState, LoopbackTransport) {
+    use crate::{controller::Controller, controller_state::ControllerState, memory::FlashMemory};
+
+    let mut controller_state = ControllerState::new(
+        Controller::ProController,
+        Some(FlashMemory::new(None, None, None).unwrap()),
+    );
+    let transport = LoopbackTransport::new();
+    controller_state.set_transport(Some(Box::new(transport.clone())));
+    (controller_state, transport)
+}
+
+impl ReportTransport for LoopbackTransport {
+    fn write(&mut self, report: &[u8]) -> io::Result<()> {
+        self.reports.lock().unwrap().push(report.to_vec());
+        Ok(())
+    }
+}